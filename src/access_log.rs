@@ -0,0 +1,351 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures_util::StreamExt;
+use serde::Serialize;
+use warp::http::{header, HeaderValue, Method, StatusCode};
+use warp::hyper::body::HttpBody;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+use crate::activity::ActiveRequest;
+use crate::config::config;
+
+/// Where the access log is written and when it is rotated
+pub struct Settings {
+    pub path: PathBuf,
+    /// The log is rotated before it grows larger than this
+    pub max_size: u64,
+    /// The log is rotated when it has been written to for this long
+    pub max_age: Duration,
+    /// Number of rotated logs kept, as `<path>.1` (newest) to `<path>.<retention>`
+    pub retention: usize,
+}
+
+/// One line of the access log
+#[derive(Serialize)]
+pub struct AccessEntry {
+    pub timestamp: String,
+    pub client_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forwarded_for: Option<String>,
+    pub route: Option<&'static str>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub upstream_url: Option<String>,
+    pub upstream_status: Option<u16>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub latency_ms: u128,
+    pub cache: Option<&'static str>,
+    /// Why the request failed, such as `timeout`, `circuit_open` or `invalid_url`
+    pub error: Option<&'static str>,
+}
+
+struct OpenLog {
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+/// JSON Lines access log with size and time based rotation
+pub struct AccessLog {
+    settings: Settings,
+    log: Mutex<Option<OpenLog>>,
+}
+
+impl AccessLog {
+    pub fn new(settings: Settings) -> Self {
+        AccessLog {
+            settings,
+            log: Mutex::new(None),
+        }
+    }
+
+    pub fn write(&self, entry: &AccessEntry) {
+        let mut line = serde_json::to_vec(entry).unwrap();
+        line.push(b'\n');
+        let mut log = self.log.lock().unwrap();
+        if let Err(err) = self.write_line(&mut log, &line) {
+            println!(
+                "Could not write access log {}: {err}",
+                self.settings.path.display()
+            );
+            *log = None;
+        }
+    }
+
+    fn write_line(&self, log: &mut Option<OpenLog>, line: &[u8]) -> io::Result<()> {
+        let settings = &self.settings;
+        let rotate = log.as_ref().is_some_and(|log| {
+            log.size > 0
+                && (log.size + line.len() as u64 > settings.max_size
+                    || log.opened.elapsed() >= settings.max_age)
+        });
+        if rotate {
+            *log = None;
+            self.rotate()?;
+        }
+        let log = match log {
+            Some(log) => log,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&settings.path)?;
+                let size = file.metadata()?.len();
+                log.insert(OpenLog {
+                    file,
+                    size,
+                    opened: Instant::now(),
+                })
+            }
+        };
+        log.file.write_all(line)?;
+        log.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// `<path>` becomes `<path>.1`, older logs move up one and the oldest is removed
+    fn rotate(&self) -> io::Result<()> {
+        let settings = &self.settings;
+        if settings.retention == 0 {
+            return ignore_missing(fs::remove_file(&settings.path));
+        }
+        let rotated = |index: usize| {
+            let mut path = settings.path.clone().into_os_string();
+            path.push(format!(".{index}"));
+            PathBuf::from(path)
+        };
+        ignore_missing(fs::remove_file(rotated(settings.retention)))?;
+        for index in (1..settings.retention).rev() {
+            ignore_missing(fs::rename(rotated(index), rotated(index + 1)))?;
+        }
+        ignore_missing(fs::rename(&settings.path, rotated(1)))
+    }
+}
+
+fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Sends entries to the access log of the running service, if it is enabled. They are
+/// written by a thread of their own, so that requests never wait for the file
+fn access_log() -> Option<&'static Sender<AccessEntry>> {
+    static ACCESS_LOG: OnceLock<Option<Sender<AccessEntry>>> = OnceLock::new();
+    ACCESS_LOG
+        .get_or_init(|| {
+            let config = config();
+            let log = AccessLog::new(Settings {
+                path: config.access_log.clone()?,
+                max_size: config.access_log_max_size,
+                max_age: config.access_log_max_age,
+                retention: config.access_log_retention,
+            });
+            Some(writer(log))
+        })
+        .as_ref()
+}
+
+/// Writes the entries sent to the returned sender, until all senders are dropped
+fn writer(log: AccessLog) -> Sender<AccessEntry> {
+    let (sender, entries) = mpsc::channel();
+    thread::Builder::new()
+        .name("access-log".to_string())
+        .spawn(move || entries.iter().for_each(|entry| log.write(&entry)))
+        .expect("failed to start the access log writer");
+    sender
+}
+
+/// The client request, as seen before it is handled
+pub struct RequestInfo {
+    pub started: Instant,
+    pub timestamp: SystemTime,
+    pub remote: Option<SocketAddr>,
+    pub forwarded_for: Option<String>,
+    pub method: Method,
+    pub path: String,
+}
+
+/// The log entry is sent when the body has been sent, or the client went away.
+/// A request attached to the response by its handler stays active until then
+struct Pending {
+    log: Option<&'static Sender<AccessEntry>>,
+    request: RequestInfo,
+    status: StatusCode,
+    active: Option<ActiveRequest>,
+    bytes_out: u64,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let Some(log) = self.log else {
+            return;
+        };
+        let request = &self.request;
+        let active = self.active.as_ref();
+        let entry = AccessEntry {
+            timestamp: humantime::format_rfc3339_millis(request.timestamp).to_string(),
+            client_ip: request.remote.map(|remote| remote.ip().to_string()),
+            forwarded_for: request.forwarded_for.clone(),
+            route: active.map(|active| active.route),
+            method: request.method.to_string(),
+            path: request.path.clone(),
+            status: self.status.as_u16(),
+            upstream_url: active.map(|active| active.url.clone()),
+            upstream_status: active.and_then(|active| active.upstream.status),
+            bytes_in: active.map_or(0, |active| active.upstream.bytes_in.load(Ordering::Relaxed)),
+            bytes_out: self.bytes_out,
+            latency_ms: request.started.elapsed().as_millis(),
+            cache: active.and_then(|active| active.upstream.cache),
+            error: active.and_then(|active| active.upstream.error),
+        };
+        // The writer only stops with the process
+        let _ = log.send(entry);
+    }
+}
+
+/// Counts the bytes of the response body and logs the request once the body is sent
+pub fn log_response(mut response: Response, request: RequestInfo) -> Response {
+    let active = response.extensions_mut().remove::<ActiveRequest>();
+    let log = access_log();
+    if log.is_none() && active.is_none() {
+        return response;
+    }
+
+    let status = response.status();
+    let body = std::mem::take(response.body_mut());
+    let known_length = body.size_hint().exact();
+    let headers = response.headers_mut();
+    if let Some(length) = known_length {
+        if status != StatusCode::NO_CONTENT && status != StatusCode::NOT_MODIFIED {
+            headers
+                .entry(header::CONTENT_LENGTH)
+                .or_insert_with(|| HeaderValue::from(length));
+        }
+    }
+
+    let mut pending = Pending {
+        log,
+        request,
+        status,
+        active,
+        bytes_out: 0,
+    };
+    let body = body.map(move |chunk| {
+        // The whole entry moves into the body, not only the counter
+        let pending = &mut pending;
+        if let Ok(ref chunk) = chunk {
+            pending.bytes_out += chunk.len() as u64;
+        }
+        chunk
+    });
+    *response.body_mut() = Body::wrap_stream(body);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(name: &str, max_size: u64) -> Settings {
+        let dir = std::env::temp_dir().join(format!("access_log_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Settings {
+            path: dir.join("access.log"),
+            max_size,
+            max_age: Duration::from_secs(3600),
+            retention: 2,
+        }
+    }
+
+    fn entry(path: &str) -> AccessEntry {
+        AccessEntry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::UNIX_EPOCH).to_string(),
+            client_ip: Some("127.0.0.1".to_string()),
+            forwarded_for: None,
+            route: Some("get"),
+            method: "GET".to_string(),
+            path: path.to_string(),
+            status: 200,
+            upstream_url: Some("https://example.com".to_string()),
+            upstream_status: Some(200),
+            bytes_in: 10,
+            bytes_out: 20,
+            latency_ms: 5,
+            cache: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn entries_should_be_json_lines() {
+        let settings = settings("lines", 1024 * 1024);
+        let path = settings.path.clone();
+        let log = AccessLog::new(settings);
+
+        log.write(&entry("/get"));
+        log.write(&entry("/raw"));
+
+        let contents = fs::read_to_string(path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["timestamp"], "1970-01-01T00:00:00.000Z");
+        assert_eq!(lines[1]["path"], "/raw");
+        assert_eq!(lines[1]["upstream_status"], 200);
+        assert!(lines[1]["cache"].is_null());
+    }
+
+    #[test]
+    fn sent_entries_should_be_written() {
+        let settings = settings("writer", 1024 * 1024);
+        let path = settings.path.clone();
+        let sender = writer(AccessLog::new(settings));
+
+        let mut entry = entry("/sent");
+        entry.error = Some("timeout");
+        sender.send(entry).unwrap();
+
+        let started = Instant::now();
+        let read = || fs::read_to_string(&path).unwrap_or_default();
+        while !read().ends_with('\n') && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let contents = read();
+        assert!(contents.contains(r#""path":"/sent""#), "{contents}");
+        assert!(contents.contains(r#""error":"timeout""#), "{contents}");
+    }
+
+    #[test]
+    fn log_should_rotate_and_keep_retention() {
+        let settings = settings("rotate", 1);
+        let path = settings.path.clone();
+        let rotated = |index: usize| PathBuf::from(format!("{}.{index}", path.display()));
+        let log = AccessLog::new(settings);
+
+        for page in ["/1", "/2", "/3", "/4"] {
+            log.write(&entry(page));
+        }
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert!(read(path.clone()).contains(r#""path":"/4""#));
+        assert!(read(rotated(1)).contains(r#""path":"/3""#));
+        assert!(read(rotated(2)).contains(r#""path":"/2""#));
+        assert!(!rotated(3).exists());
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use serde::Serialize;
+use warp::reply::Response;
+use warp::Reply;
+
+use crate::page_types::PageContent;
+
+/// A proxied request that is being handled
+#[derive(Serialize)]
+pub struct RequestInfo {
+    pub route: &'static str,
+    pub method: String,
+    pub url: String,
+    pub elapsed_ms: u128,
+}
+
+struct Active {
+    route: &'static str,
+    method: String,
+    url: String,
+    started: Instant,
+}
+
+fn active() -> &'static Mutex<BTreeMap<u64, Active>> {
+    static ACTIVE: OnceLock<Mutex<BTreeMap<u64, Active>>> = OnceLock::new();
+    ACTIVE.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// Registers a request as active until the returned guard is dropped
+pub fn track(route: &'static str, method: &str, url: &str) -> ActiveRequest {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    active().lock().unwrap().insert(
+        id,
+        Active {
+            route,
+            method: method.to_string(),
+            url: url.to_string(),
+            started: Instant::now(),
+        },
+    );
+    ActiveRequest {
+        id,
+        route,
+        url: url.to_string(),
+        upstream: Upstream::default(),
+    }
+}
+
+/// All active requests, oldest first
+pub fn active_requests() -> Vec<RequestInfo> {
+    active()
+        .lock()
+        .unwrap()
+        .values()
+        .map(|active| RequestInfo {
+            route: active.route,
+            method: active.method.clone(),
+            url: active.url.clone(),
+            elapsed_ms: active.started.elapsed().as_millis(),
+        })
+        .collect()
+}
+
+/// What happened upstream, for the access log
+#[derive(Default)]
+pub struct Upstream {
+    pub status: Option<u16>,
+    /// Body bytes received from upstream, counted while streaming
+    pub bytes_in: Arc<AtomicU64>,
+    /// The code of the error, such as `timeout` or `circuit_open`
+    pub error: Option<&'static str>,
+    /// Whether the page came from the cache
+    pub cache: Option<&'static str>,
+}
+
+/// Keeps a request in the active requests until it is dropped
+pub struct ActiveRequest {
+    id: u64,
+    pub route: &'static str,
+    pub url: String,
+    pub upstream: Upstream,
+}
+
+impl ActiveRequest {
+    /// Records the upstream outcome from the fetched page
+    pub fn record(&mut self, content: &PageContent) {
+        let bytes_in = content
+            .contents
+            .as_ref()
+            .map(|contents| contents.len() as u64)
+            .or(content.content_length)
+            .unwrap_or(0);
+        self.upstream.status = content.http_code;
+        self.upstream.bytes_in.store(bytes_in, Ordering::Relaxed);
+        self.upstream.error = content.error.as_ref().and(content.error_code);
+        self.upstream.cache = content.cache;
+    }
+
+    /// The response carries the request, which stays active until the response is sent
+    pub fn attach(self, reply: impl Reply) -> Response {
+        let mut response = reply.into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        active().lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_should_be_active_until_dropped() {
+        let url = "https://activity.test/tracked";
+        let is_active = || active_requests().iter().any(|request| request.url == url);
+
+        let guard = track("get", "GET", url);
+        assert!(is_active());
+        drop(guard);
+        assert!(!is_active());
+    }
+}
@@ -0,0 +1,255 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::activity::active_requests;
+use crate::cache::cache;
+use crate::circuit_breaker::circuit_breaker;
+use crate::config::config;
+use crate::rate_limit::rate_limiter;
+use crate::shutdown::draining;
+
+static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+
+/// Proxy routes answer 503 while in maintenance
+pub fn in_maintenance() -> bool {
+    MAINTENANCE.load(Ordering::Relaxed)
+}
+
+#[derive(Deserialize, Serialize)]
+struct Maintenance {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct Purge {
+    url: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Purged {
+    purged: usize,
+}
+
+/// Only requests with `Authorization: Bearer <token>` are let through
+fn authorized(token: String) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    // Compared as hashes in constant time, so the time taken tells nothing about the token
+    let expected = Sha256::digest(format!("Bearer {token}"));
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let authorization = Sha256::digest(authorization.unwrap_or_default());
+            let authorized = bool::from(authorization.ct_eq(&expected));
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+async fn unauthorized(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(StatusCode::UNAUTHORIZED)
+    } else {
+        Err(rejection)
+    }
+}
+
+/// The current configuration
+fn config_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path!("config")
+        .and(warp::get())
+        .map(|| warp::reply::json(config()))
+}
+
+/// The proxied requests that are being handled
+fn requests_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path!("requests")
+        .and(warp::get())
+        .map(|| warp::reply::json(&active_requests()))
+}
+
+/// The circuit breaker states, per upstream host
+fn circuits_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path!("circuits")
+        .and(warp::get())
+        .map(|| warp::reply::json(&circuit_breaker().status()))
+}
+
+/// The cache statistics, or purge it with `DELETE`, only the pages of one URL with `?url=`.
+/// Not found when the cache is disabled
+fn cache_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    let get = warp::get().then(|| async {
+        match cache() {
+            Some(cache) => warp::reply::json(&cache.stats().await).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    });
+    let delete = warp::delete()
+        .and(warp::query::<Purge>())
+        .then(|purge: Purge| async move {
+            match cache() {
+                Some(cache) => {
+                    let purged = cache.purge(purge.url.as_deref()).await;
+                    println!("Purged {purged} pages from the cache");
+                    warp::reply::json(&Purged { purged }).into_response()
+                }
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        });
+    warp::path!("cache").and(get.or(delete))
+}
+
+/// Allowed and limited requests per client, not found when there is no rate limit
+fn rate_limits_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path!("rate-limits")
+        .and(warp::get())
+        .map(|| match rate_limiter() {
+            Some(limiter) => warp::reply::json(&limiter.counters()).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        })
+}
+
+/// Read or toggle maintenance with `{"enabled": true}`
+fn maintenance_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    let get = warp::get().map(|| {
+        warp::reply::json(&Maintenance {
+            enabled: in_maintenance(),
+        })
+    });
+    let put = warp::put()
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .map(|maintenance: Maintenance| {
+            println!("Maintenance {}", maintenance.enabled);
+            MAINTENANCE.store(maintenance.enabled, Ordering::Relaxed);
+            warp::reply::json(&maintenance)
+        });
+    warp::path!("maintenance").and(get.or(put))
+}
+
+pub fn admin_filters(
+    token: String,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    authorized(token)
+        .and(
+            config_filter()
+                .or(requests_filter())
+                .or(circuits_filter())
+                .or(rate_limits_filter())
+                .or(cache_filter())
+                .or(maintenance_filter()),
+        )
+        .recover(unauthorized)
+}
+
+/// Start the admin service, if an admin token is configured
+pub fn start() -> Option<impl Future<Output = ()>> {
+    let config = config();
+    let Some(token) = config.admin_token.clone() else {
+        println!("No admin token, the admin service is not started");
+        return None;
+    };
+
+    println!("Admin listening on {}", config.admin_address);
+    let (_admin_addr, admin_server) = warp::serve(admin_filters(token))
+        .bind_with_graceful_shutdown(config.admin_address, draining());
+    Some(admin_server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use warp::test::request;
+
+    #[tokio::test]
+    async fn admin_should_require_token() {
+        let filters = admin_filters("secret".to_string());
+
+        let response = request().path("/config").reply(&filters).await;
+        assert_eq!(response.status(), 401);
+
+        let response = request()
+            .path("/config")
+            .header("Authorization", "Bearer wrong")
+            .reply(&filters)
+            .await;
+        assert_eq!(response.status(), 401);
+
+        let response = request()
+            .path("/config")
+            .header("Authorization", "Bearer secret")
+            .reply(&filters)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body["batch_concurrency"].is_number());
+
+        // Without a rate limit there are no counters
+        let response = request()
+            .path("/rate-limits")
+            .header("Authorization", "Bearer secret")
+            .reply(&filters)
+            .await;
+        assert_eq!(response.status(), 404);
+        assert!(body.get("admin_token").is_none());
+    }
+
+    #[tokio::test]
+    async fn requests_should_list_active_requests() {
+        let filters = admin_filters("secret".to_string());
+        let _active = crate::activity::track("get", "GET", "https://admin.test/active");
+
+        let response = request()
+            .path("/requests")
+            .header("Authorization", "Bearer secret")
+            .reply(&filters)
+            .await;
+
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|r| r["url"] == "https://admin.test/active" && r["route"] == "get"));
+    }
+
+    #[tokio::test]
+    async fn cache_should_report_stats_and_purge() {
+        let filters = admin_filters("secret".to_string());
+
+        let response = request()
+            .path("/cache")
+            .header("Authorization", "Bearer secret")
+            .reply(&filters)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body["max_size"].as_u64().unwrap() > 0);
+        assert!(body["hits"].is_number());
+
+        let response = request()
+            .method("DELETE")
+            .path("/cache?url=https://admin.test/never-cached")
+            .header("Authorization", "Bearer secret")
+            .reply(&filters)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["purged"], 0);
+    }
+}
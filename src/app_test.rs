@@ -1,12 +1,19 @@
 /// Integration test
 #[cfg(test)]
 mod tests {
+    use crate::admin::admin_filters;
+    use crate::config::config;
     use crate::server::all_filters;
+    use async_compression::tokio::bufread::GzipDecoder;
+    use futures_util::{SinkExt, StreamExt};
     use serde_json::Value;
     use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
     use warp::http::header;
     use warp::test::request;
-    use wiremock::matchers::{method, path};
+    use warp::ws::Message;
+    use wiremock::matchers::{self, method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
     #[tokio::test]
@@ -119,6 +126,42 @@ mod tests {
         assert_eq!(body, "Hi, allOrigins!");
     }
 
+    #[tokio::test]
+    async fn raw_request_with_rewrite_should_proxy_links() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/page.html"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(r#"<a href="/test.html">a</a>"#, "text/html"),
+            )
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/raw?url={example_uri}/page.html&rewrite=true").as_str())
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 200);
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let expected_link = format!("{example_uri}/test.html")
+            .replace(':', "%3A")
+            .replace('/', "%2F");
+        let expected = format!(r#"<a href="/raw?url={expected_link}&amp;rewrite=true">a</a>"#);
+
+        assert_eq!(body, expected);
+        // The page is rewritten as it arrives, so its length is not known up front
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+    }
+
     #[tokio::test]
     async fn raw_request_to_error_should_return_json_structure() {
         let server = setup().await;
@@ -271,18 +314,889 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn not_supplying_url_is_an_error() {
-        let _server = setup().await;
+    async fn json_should_be_transcoded_to_charset() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/cafe.txt"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("café ☕", "text/plain"))
+            .mount(&server)
+            .await;
 
         let response = request()
-            .path(format!("/get").as_str())
+            .path(format!("/get?url={example_uri}/cafe.txt&charset=iso-8859-1").as_str())
             .reply(&all_filters())
             .await;
 
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/json; charset=windows-1252"
+        );
+        assert!(response.body().is_ascii());
+        let response_body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(response_body["contents"], "café ☕");
+
+        let response = request()
+            .path(format!("/get?url={example_uri}/cafe.txt&charset=nope").as_str())
+            .reply(&all_filters())
+            .await;
         assert_eq!(response.status(), 400);
-        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+    }
+
+    #[tokio::test]
+    async fn meta_charset_should_be_detected_and_decoded() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/latin.html"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(&b"<meta charset=\"iso-8859-1\">caf\xe9"[..], "text/html"),
+            )
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/get?url={example_uri}/latin.html").as_str())
+            .reply(&all_filters())
+            .await;
+
+        let response_body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            response_body["contents"],
+            "<meta charset=\"iso-8859-1\">café"
+        );
+        assert_eq!(response_body["detected_charset"], "windows-1252");
+    }
+
+    #[tokio::test]
+    async fn raw_request_with_charset_should_transcode() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/latin.txt"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(&b"caf\xe9"[..], "text/plain; charset=iso-8859-1"),
+            )
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/raw?url={example_uri}/latin.txt&charset=utf-8").as_str())
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body().as_ref(), "café".as_bytes());
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=UTF-8"
+        );
+
+        let response = request()
+            .path(format!("/raw?url={example_uri}/latin.txt&charset=shift_jis").as_str())
+            .reply(&all_filters())
+            .await;
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=Shift_JIS"
+        );
+
+        let response = request()
+            .path(format!("/raw?url={example_uri}/latin.txt&charset=nope").as_str())
+            .reply(&all_filters())
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn matching_etag_should_be_not_modified() {
+        let server = setup().await;
+        let example_uri = server.uri();
+        let path = format!("/get?url={example_uri}/test.html");
+
+        let response = request().path(&path).reply(&all_filters()).await;
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(etag.starts_with("W/\""));
+
+        let response = request()
+            .path(&path)
+            .header("If-None-Match", &etag)
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cached_page_should_be_revalidated_upstream() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/versioned.txt"))
+            .and(matchers::header("If-None-Match", "\"v1\""))
+            .respond_with(ResponseTemplate::new(304))
+            .with_priority(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/versioned.txt"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw("version 1", "text/plain")
+                    .insert_header("ETag", "\"v1\""),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        for _ in 0..2 {
+            let response = request()
+                .path(format!("/get?url={example_uri}/versioned.txt").as_str())
+                .reply(&all_filters())
+                .await;
+
+            let response_body: Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(response_body["contents"], "version 1");
+            assert_eq!(response_body["http_code"], 200);
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_page_should_be_served_from_cache() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        for verb in ["GET", "HEAD"] {
+            Mock::given(method(verb))
+                .and(path("/cached.txt"))
+                .and(matchers::header("If-None-Match", "\"c1\""))
+                .respond_with(ResponseTemplate::new(304))
+                .with_priority(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/cached.txt"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw("cached body", "text/plain")
+                    .insert_header("ETag", "\"c1\""),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        for _ in 0..2 {
+            let response = request()
+                .path(format!("/raw?url={example_uri}/cached.txt").as_str())
+                .reply(&all_filters())
+                .await;
+
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.body(), "cached body");
+            assert_eq!(response.headers()[header::ETAG], "\"c1\"");
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        }
+
+        let response = request()
+            .path(format!("/info?url={example_uri}/cached.txt").as_str())
+            .reply(&all_filters())
+            .await;
+        let response_body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(response_body["content_length"], 11);
+        assert_eq!(response_body["http_code"], 200);
+    }
+
+    #[tokio::test]
+    async fn raw_should_relay_not_modified_from_upstream() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/unchanged.txt"))
+            .and(matchers::header("If-None-Match", "\"r1\""))
+            .respond_with(ResponseTemplate::new(304).insert_header("ETag", "\"r1\""))
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/raw?url={example_uri}/unchanged.txt").as_str())
+            .header("If-None-Match", "\"r1\"")
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()[header::ETAG], "\"r1\"");
+    }
+
+    #[tokio::test]
+    async fn raw_should_relay_ranges() {
+        let server = setup().await;
+        let example_uri = server.uri();
+        let multipart = "multipart/byteranges; boundary=RANGE";
+        let parts = "--RANGE\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                     --RANGE\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--RANGE--\r\n";
+
+        Mock::given(method("GET"))
+            .and(path("/video.bin"))
+            .and(matchers::header("Range", "bytes=2-5"))
+            .respond_with(
+                ResponseTemplate::new(206)
+                    .set_body_raw("2345", "application/octet-stream")
+                    .insert_header("Content-Range", "bytes 2-5/10")
+                    .insert_header("Accept-Ranges", "bytes"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/video.bin"))
+            .and(matchers::headers("Range", vec!["bytes=0-1", "8-9"]))
+            .respond_with(ResponseTemplate::new(206).set_body_raw(parts, multipart))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/video.bin"))
+            .and(matchers::header("Range", "bytes=20-"))
+            .respond_with(ResponseTemplate::new(416).insert_header("Content-Range", "bytes */10"))
+            .mount(&server)
+            .await;
+
+        let path = format!("/raw?url={example_uri}/video.bin");
+        let raw = |range: &'static str| {
+            let path = path.clone();
+            async move {
+                let filters = all_filters();
+                request()
+                    .path(&path)
+                    .header("Range", range)
+                    .reply(&filters)
+                    .await
+            }
+        };
+
+        let response = raw("bytes=2-5").await;
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.body().as_ref(), b"2345");
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+
+        let response = raw("bytes=0-1,8-9").await;
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], multipart);
+        assert_eq!(response.body().as_ref(), parts.as_bytes());
+
+        let response = raw("bytes=20-").await;
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn supplying_headers_should_add_upstream_headers() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        let response = request()
+            .path(format!("/get?url={example_uri}/test.html&headers=true").as_str())
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 200);
+
         let body = String::from_utf8(response.body().to_vec()).unwrap();
-        assert_eq!(body, "No 'url' query parameter");
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+
+        assert_eq!(
+            response_body["headers"]["content-type"][0].as_str(),
+            Some("text/example")
+        );
+        assert_eq!(
+            response_body["headers"]["content-length"][0].as_str(),
+            Some("15")
+        );
+    }
+
+    #[tokio::test]
+    async fn preview_request_should_return_metadata() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/page.html"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"<html><head><title>Title</title><meta property="og:image" content="/i.png"></head></html>"#,
+                "text/html; charset=utf-8",
+            ))
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/preview?url={example_uri}/page.html").as_str())
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+
+        assert_eq!(response_body["title"].as_str(), Some("Title"));
+        assert_eq!(
+            response_body["image"].as_str(),
+            Some(format!("{example_uri}/i.png").as_str())
+        );
+        assert_eq!(
+            response_body["favicon"].as_str(),
+            Some(format!("{example_uri}/favicon.ico").as_str())
+        );
+        assert_eq!(response_body["http_code"].as_i64(), Some(200));
+        assert!(response_body["error"].is_null());
+    }
+
+    #[tokio::test]
+    async fn preview_request_of_other_content_is_an_error() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        let response = request()
+            .path(format!("/preview?url={example_uri}/test.html").as_str())
+            .reply(&all_filters())
+            .await;
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+
+        assert_eq!(response_body["error"].as_str(), Some("Not an HTML page"));
+        assert!(response_body["title"].is_null());
+    }
+
+    #[tokio::test]
+    async fn select_request_should_return_matches() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/list.html"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"<a href="/1">One</a><a href="/2">Two</a><a href="/3">Three</a>"#,
+                "text/html",
+            ))
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(
+                format!("/select?url={example_uri}/list.html&selector=a&extract=attr:href&limit=2")
+                    .as_str(),
+            )
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+
+        assert_eq!(response_body["matches"], serde_json::json!(["/1", "/2"]));
+        assert_eq!(response_body["truncated"].as_bool(), Some(true));
+        assert_eq!(response_body["http_code"].as_i64(), Some(200));
+        assert!(response_body["error"].is_null());
+    }
+
+    #[tokio::test]
+    async fn select_request_should_reject_pages_that_are_not_html() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/list.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("[]", "application/json"))
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/select?url={example_uri}/list.json&selector=a").as_str())
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 415);
+        let response_body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(response_body["error"].as_str(), Some("Not an HTML page"));
+    }
+
+    #[tokio::test]
+    async fn select_request_should_reject_large_pages() {
+        let server = setup().await;
+        let example_uri = server.uri();
+        let large = "<a>a</a>".repeat(config().select_max_document_size / 8 + 1);
+
+        Mock::given(method("GET"))
+            .and(path("/large.html"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(large, "text/html"))
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/select?url={example_uri}/large.html&selector=a").as_str())
+            .reply(&all_filters())
+            .await;
+
+        let response_body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(response_body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Page is larger than"));
+        assert!(response_body["matches"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn select_request_without_selector_is_an_error() {
+        let response = request()
+            .path("/select?url=http://localhost/")
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 400);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert_eq!(body, "No 'selector' query parameter");
+    }
+
+    #[tokio::test]
+    async fn batch_request_should_return_results_in_order() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        let response = request()
+            .method("POST")
+            .path("/batch")
+            .json(&serde_json::json!([
+                {"url": format!("{example_uri}/test.html")},
+                {"url": format!("{example_uri}/test.html"), "method": "post"},
+                {"url": format!("{example_uri}/not-found.html")},
+                {"url": format!("{example_uri}/test.html"), "method": "BAD METHOD"},
+            ]))
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+
+        let results = response_body.as_array().unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0]["contents"].as_str(), Some("Hi, allOrigins!"));
+        assert_eq!(results[1]["http_code"].as_i64(), Some(201));
+        assert_eq!(results[2]["error"].as_str(), Some("404 Not Found"));
+        assert_eq!(
+            results[3]["error"].as_str(),
+            Some("Invalid method BAD METHOD")
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_request_should_forward_headers() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/with-header.html"))
+            .and(matchers::header("x-token", "secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Got header"))
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .method("POST")
+            .path("/batch")
+            .json(&serde_json::json!([
+                {"url": format!("{example_uri}/with-header.html"), "headers": {"x-token": "secret"}},
+            ]))
+            .reply(&all_filters())
+            .await;
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+
+        assert_eq!(response_body[0]["contents"].as_str(), Some("Got header"));
+    }
+
+    #[tokio::test]
+    async fn batch_request_can_be_streamed_as_json_lines() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        let response = request()
+            .method("POST")
+            .path("/batch")
+            .header("Accept", "application/x-ndjson")
+            .json(&serde_json::json!([
+                {"url": format!("{example_uri}/test.html")},
+                {"url": format!("{example_uri}/not-found.html")},
+            ]))
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-ndjson"
+        );
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let mut lines: Vec<Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Failed to parse JSON line"))
+            .collect();
+        lines.sort_by_key(|line| line["index"].as_i64());
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["contents"].as_str(), Some("Hi, allOrigins!"));
+        assert_eq!(lines[1]["http_code"].as_i64(), Some(404));
+    }
+
+    #[tokio::test]
+    async fn batch_request_with_invalid_body_is_an_error() {
+        let response = request()
+            .method("POST")
+            .path("/batch")
+            .body("not json")
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn filter_should_select_part_of_json() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/data.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"{"items": [{"name": "a"}, {"name": "b"}]}"#,
+                "application/json",
+            ))
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/get?url={example_uri}/data.json&filter=items%5B%5D.name").as_str())
+            .reply(&all_filters())
+            .await;
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+
+        assert_eq!(response_body["contents"].as_str(), Some(r#"["a","b"]"#));
+        assert_eq!(response_body["content_length"].as_i64(), Some(9));
+        assert!(response_body["error"].is_null());
+
+        let response = request()
+            .path(format!("/raw?url={example_uri}/data.json&filter=items%5B0%5D").as_str())
+            .reply(&all_filters())
+            .await;
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert_eq!(body, r#"{"name":"a"}"#);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    }
+
+    #[tokio::test]
+    async fn filter_of_other_content_should_return_error() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        let response = request()
+            .path(format!("/get?url={example_uri}/test.html&filter=a").as_str())
+            .reply(&all_filters())
+            .await;
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+
+        assert!(response_body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid JSON"));
+        assert_eq!(response_body["http_code"].as_i64(), Some(200));
+    }
+
+    #[tokio::test]
+    async fn large_response_should_be_compressed_when_accepted() {
+        let server = setup().await;
+        let example_uri = server.uri();
+        let large = "Hi, allOrigins! ".repeat(200);
+
+        Mock::given(method("GET"))
+            .and(path("/large.txt"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(large.clone(), "text/plain"))
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/raw?url={example_uri}/large.txt").as_str())
+            .header("Accept-Encoding", "deflate, gzip")
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+
+        let mut decoder = GzipDecoder::new(response.body().as_ref());
+        let mut body = String::new();
+        decoder.read_to_string(&mut body).await.unwrap();
+        assert_eq!(body, large);
+    }
+
+    #[tokio::test]
+    async fn small_response_should_not_be_compressed() {
+        let server = setup().await;
+        let example_uri = server.uri();
+
+        let response = request()
+            .path(format!("/get?url={example_uri}/test.html").as_str())
+            .header("Accept-Encoding", "gzip, br")
+            .reply(&all_filters())
+            .await;
+
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+    }
+
+    #[tokio::test]
+    async fn failing_host_should_open_circuit() {
+        let server = MockServer::start().await;
+        // Mock servers are reused between tests, a separate host name keeps the open circuit here
+        let example_uri = server.uri().replace("127.0.0.1", "localhost");
+        let host = example_uri.trim_start_matches("http://").to_string();
+
+        Mock::given(method("GET"))
+            .and(path("/failing"))
+            .respond_with(ResponseTemplate::new(503))
+            // Ten requests open the circuit, each tried three times
+            .expect(30)
+            .mount(&server)
+            .await;
+
+        for _ in 0..11 {
+            request()
+                .path(format!("/get?url={example_uri}/failing").as_str())
+                .reply(&all_filters())
+                .await;
+        }
+        let response = request()
+            .path(format!("/get?url={example_uri}/failing").as_str())
+            .reply(&all_filters())
+            .await;
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+        assert_eq!(response_body["error"].as_str(), Some("circuit_open"));
+        assert!(response_body["http_code"].is_null());
+
+        let response = request()
+            .path("/circuits")
+            .header("Authorization", "Bearer token")
+            .reply(&admin_filters("token".to_string()))
+            .await;
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+        assert_eq!(response_body[&host]["state"].as_str(), Some("open"));
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn event_stream_should_be_relayed_untransformed() {
+        let server = MockServer::start().await;
+        let example_uri = server.uri();
+        Mock::given(method("GET"))
+            .and(path("/events"))
+            .and(matchers::header("last-event-id", "5"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw("id: 6\ndata: <a href=\"/x\">\n\n", "text/event-stream"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = request()
+            .path(format!("/raw?url={example_uri}/events&rewrite=true").as_str())
+            .header("Last-Event-ID", "5")
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(response.body(), "id: 6\ndata: <a href=\"/x\">\n\n");
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn websocket_should_be_bridged_to_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_uri = format!("ws://{}/echo", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut upstream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = upstream.next().await {
+                if message.is_text() || message.is_binary() {
+                    upstream.send(message).await.unwrap();
+                }
+            }
+        });
+
+        let mut client = warp::test::ws()
+            .path(format!("/ws?url={upstream_uri}").as_str())
+            .handshake(all_filters())
+            .await
+            .expect("handshake");
+        client.send(Message::text("hello")).await;
+        assert_eq!(client.recv().await.unwrap().to_str(), Ok("hello"));
+        client.send(Message::binary(vec![1, 2, 3])).await;
+        assert_eq!(client.recv().await.unwrap().as_bytes(), &[1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn websocket_should_require_websocket_url() {
+        let response = request()
+            .path("/ws?url=http://example.com")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .reply(&all_filters())
+            .await;
+
+        assert_eq!(response.status(), 400);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_should_serve_requests() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::UnixStream;
+
+        let socket = std::env::temp_dir().join(format!("all_origins_{}.sock", std::process::id()));
+        let server = tokio::spawn(crate::server::serve_unix(&socket, 0o600).unwrap());
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let temp_files = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(|file| {
+                file.file_name()
+                    .to_string_lossy()
+                    .starts_with(".all_origins_")
+            });
+        assert_eq!(temp_files.count(), 0);
+
+        let mut stream = UnixStream::connect(&socket).await.unwrap();
+        stream
+            .write_all(
+                b"GET /openapi.json HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        server.abort();
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn service_should_be_ready() {
+        let response = request().path("/ready").reply(&all_filters()).await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "ready");
+    }
+
+    #[tokio::test]
+    async fn openapi_document_should_describe_routes() {
+        let response = request().path("/openapi.json").reply(&all_filters()).await;
+
+        assert_eq!(response.status(), 200);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let response_body: Value =
+            serde_json::from_str(body.as_str()).expect("Failed to parse JSON response");
+        assert!(response_body["openapi"].as_str().unwrap().starts_with("3."));
+        for route in ["/get", "/raw", "/info"] {
+            assert!(response_body["paths"][route]["get"].is_object(), "{route}");
+        }
+        assert!(response_body["components"]["schemas"]["PageContent"].is_object());
+    }
+
+    #[tokio::test]
+    async fn not_supplying_url_is_an_error() {
+        let _server = setup().await;
+
+        let response = request().path("/get").reply(&all_filters()).await;
+
+        assert_eq!(response.status(), 400);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert_eq!(body, "No 'url' query parameter");
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_should_be_answered_like_warp() {
+        let response = request().path("/unknown").reply(&all_filters()).await;
+        assert_eq!(response.status(), 404);
+        assert!(response.body().is_empty());
+
+        let response = request().path("/batch").reply(&all_filters()).await;
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.body(), "HTTP method not allowed");
+
+        let response = request()
+            .method("POST")
+            .path("/batch")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body("not json")
+            .reply(&all_filters())
+            .await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
     }
 
     async fn setup() -> MockServer {
@@ -0,0 +1,103 @@
+use std::sync::OnceLock;
+
+use reqwest::ClientBuilder;
+use warp::filters::BoxedFilter;
+use warp::reply::Response;
+use warp::Filter;
+
+use crate::config::{set_config, Config};
+use crate::get_page::{set_client_hook, ClientHook};
+use crate::server::mounted_filters;
+
+/// Builds the filter of the service, to mount it in another warp application:
+/// ```no_run
+/// # use warp::Filter;
+/// let proxy = all_origins_rust::Builder::new()
+///     .prefix("proxy")
+///     .client(|client| client.timeout(std::time::Duration::from_secs(10)))
+///     .build()
+///     .unwrap();
+/// let routes = proxy.or(warp::path("hello").map(|| "Hello"));
+/// ```
+/// The configuration, client and prefix are those of the whole process, they can only be set
+/// once, before the first request
+#[derive(Default)]
+pub struct Builder {
+    config: Option<Config>,
+    client: Option<ClientHook>,
+    prefix: Vec<String>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The configuration, like the signing secret, retries and circuit breaker. Read from the
+    /// environment when not set
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Changes the client of upstream HTTP requests, like its proxy or timeouts
+    pub fn client<F>(mut self, client: F) -> Self
+    where
+        F: Fn(ClientBuilder) -> ClientBuilder + Send + Sync + 'static,
+    {
+        self.client = Some(Box::new(client));
+        self
+    }
+
+    /// The path the routes are under, like `proxy` for `/proxy/get`. Links of rewritten pages
+    /// include it, signatures do not: they cover the route, like `/raw`
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(String::from)
+            .collect();
+        self
+    }
+
+    /// The routes of the service. Requests they reject are left to the application, and only
+    /// the requests they answer are in the access log. Fails when the configuration or client
+    /// was already set or used, or the routes were built under another prefix
+    pub fn build(self) -> Result<BoxedFilter<(Response,)>, String> {
+        let path: String = self.prefix.iter().map(|s| format!("/{s}")).collect();
+        if *MOUNT_PATH.get_or_init(|| path.clone()) != path {
+            return Err("The routes are already built under another prefix".to_string());
+        }
+        if let Some(config) = self.config {
+            set_config(config)?;
+        }
+        if let Some(client) = self.client {
+            set_client_hook(client)?;
+        }
+        let mut prefix = warp::any().boxed();
+        for segment in self.prefix {
+            prefix = prefix.and(warp::path(segment)).boxed();
+        }
+        Ok(prefix.and(mounted_filters()).boxed())
+    }
+}
+
+static MOUNT_PATH: OnceLock<String> = OnceLock::new();
+
+/// The path the routes are under, like `/proxy`. Empty when they are at the root
+pub(crate) fn mount_path() -> &'static str {
+    MOUNT_PATH.get().map_or("", String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config;
+
+    #[test]
+    fn config_should_only_be_set_before_use() {
+        config();
+        let built = Builder::new().config(Config::from_env()).build();
+        assert_eq!(built.err().unwrap(), "The configuration is already in use");
+    }
+}
@@ -0,0 +1,888 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use futures_util::future::BoxFuture;
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::hyper::body::Bytes;
+
+use crate::config::config;
+use crate::page_types::PageContent;
+use crate::redis_cache::RedisCache;
+
+/// The page was served from the cache, after upstream answered that it was not modified
+pub const HIT: &str = "hit";
+/// The page could be cached, but was fetched in full
+pub const MISS: &str = "miss";
+
+const INDEX_FILE: &str = "index.json";
+const BODIES_DIR: &str = "bodies";
+const JOURNAL_FILE: &str = "journal.jsonl";
+/// The index is written in full once the journal has more lines than this, or than entries
+const COMPACT_AFTER: usize = 1000;
+
+/// Pages without an `ETag` or `Last-Modified` can not be revalidated, so they are not kept
+pub fn is_revalidatable(content: &PageContent) -> bool {
+    content.http_code == Some(200)
+        && content.error.is_none()
+        && (content.upstream_etag.is_some() || content.last_modified.is_some())
+}
+
+/// An upstream response in the cache
+#[derive(Clone)]
+pub struct CachedPage {
+    /// The page without its contents
+    pub content: PageContent,
+    /// The body as it was received
+    pub body: Bytes,
+}
+
+impl CachedPage {
+    /// The page with its contents
+    pub fn page_content(&self) -> PageContent {
+        PageContent {
+            cache: Some(HIT),
+            ..self.content.clone().with_body(&self.body)
+        }
+    }
+}
+
+/// Where cached pages are kept. Backends do not fail: a page that can not be read is a miss,
+/// one that can not be written is not kept
+pub trait CacheBackend: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<CachedPage>>;
+
+    /// Keeps the page, with `body` as it was received
+    fn insert<'a>(
+        &'a self,
+        key: &'a str,
+        url: &'a str,
+        content: &'a PageContent,
+        body: Bytes,
+    ) -> BoxFuture<'a, ()>;
+
+    /// Removes all pages, or those of one URL. Returns the number of removed pages
+    fn purge<'a>(&'a self, url: Option<&'a str>) -> BoxFuture<'a, usize>;
+
+    fn stats(&self) -> BoxFuture<'_, CacheStats>;
+}
+
+/// What is stored about a cached page, apart from its body
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    /// The requested URL, the page has the URL after redirects
+    pub url: String,
+    content: PageContent,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Metadata {
+    /// The page without its contents and what changes with each request
+    pub fn new(url: &str, content: &PageContent) -> Self {
+        let mut content = PageContent {
+            contents: None,
+            cache: None,
+            response_time: 0,
+            retries: 0,
+            ..content.clone()
+        };
+        Metadata {
+            url: url.to_string(),
+            etag: content.upstream_etag.take(),
+            last_modified: content.last_modified.take(),
+            content,
+        }
+    }
+
+    pub fn cached_page(&self, body: Bytes) -> CachedPage {
+        let mut content = self.content.clone();
+        content.upstream_etag = self.etag.clone();
+        content.last_modified = self.last_modified.clone();
+        CachedPage { content, body }
+    }
+}
+
+/// The metadata of a page in a local cache, bodies are stored by their hash
+#[derive(Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    #[serde(flatten)]
+    metadata: Metadata,
+    body: String,
+    size: u64,
+    last_used: u64,
+    /// Milliseconds since the epoch after which the page is dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+}
+
+impl IndexEntry {
+    fn cached_page(&self, body: Bytes) -> CachedPage {
+        self.metadata.cached_page(body)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now_millis())
+    }
+}
+
+pub(crate) fn now_millis() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    since_epoch.unwrap_or_default().as_millis() as u64
+}
+
+/// A body and the number of entries that have it
+struct StoredBody {
+    size: u64,
+    refs: usize,
+    /// The body itself when the cache is in memory, on disk it is in a file
+    bytes: Option<Bytes>,
+}
+
+/// A change to the index, one JSON line in the journal
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JournalOp<'a> {
+    Insert {
+        key: Cow<'a, str>,
+        entry: Box<Cow<'a, IndexEntry>>,
+    },
+    Remove {
+        key: Cow<'a, str>,
+    },
+}
+
+/// The changes to the index since it was last written in full. Appending a line is cheap,
+/// where writing the index takes time with the number of entries
+#[derive(Default)]
+struct Journal {
+    file: Option<File>,
+    len: usize,
+}
+
+impl Journal {
+    fn append(&mut self, op: &JournalOp) {
+        let Some(ref mut file) = self.file else {
+            return;
+        };
+        let mut line = serde_json::to_vec(op).unwrap();
+        line.push(b'\n');
+        if let Err(err) = file.write_all(&line) {
+            println!("Could not write cache journal: {err}");
+        }
+        self.len += 1;
+    }
+}
+
+#[derive(Default)]
+struct State {
+    index: HashMap<String, IndexEntry>,
+    bodies: HashMap<String, StoredBody>,
+    size: u64,
+    clock: u64,
+    journal: Journal,
+}
+
+/// The cache for the admin API, backends only fill in what they know
+#[derive(Serialize, Default)]
+pub struct CacheStats {
+    pub backend: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bodies: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    pub max_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Pages kept by this process, in memory or in a directory.
+/// Bodies are stored once per content hash, next to an index with the metadata of each page.
+/// With a directory the cache is kept on disk and survives restarts. The least recently used
+/// pages are dropped when it grows larger than `max_size`, and all pages after `ttl`.
+/// A directory must only be used by one process at a time, the index is not shared
+pub struct LocalCache {
+    max_size: u64,
+    ttl: Option<Duration>,
+    dir: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+fn hash(body: &[u8]) -> String {
+    Sha256::digest(body)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The bytes an entry takes, apart from its body
+fn metadata_size(key: &str, entry: &IndexEntry) -> u64 {
+    let metadata = &entry.metadata;
+    (key.len() + metadata.url.len() + metadata.content.url.len() + 128) as u64
+}
+
+/// Writes the file as a whole or not at all, readers never see a partial file
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp-{}-{id}", std::process::id()));
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+impl LocalCache {
+    pub fn in_memory(max_size: u64, ttl: Option<Duration>) -> Self {
+        LocalCache {
+            max_size,
+            ttl,
+            dir: None,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Opens the cache in `dir`, with the pages kept there before: the index, and the changes
+    /// in the journal since it was written. Entries without their body and bodies without
+    /// entries, left by a crash, are removed
+    pub fn open(dir: &Path, max_size: u64, ttl: Option<Duration>) -> io::Result<Self> {
+        fs::create_dir_all(dir.join(BODIES_DIR))?;
+        let mut index: HashMap<String, IndexEntry> = fs::read(dir.join(INDEX_FILE))
+            .ok()
+            .and_then(|index| serde_json::from_slice(&index).ok())
+            .unwrap_or_default();
+        if let Ok(journal) = File::open(dir.join(JOURNAL_FILE)) {
+            for line in BufReader::new(journal).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                match serde_json::from_str(&line) {
+                    Ok(JournalOp::Insert { key, entry }) => {
+                        index.insert(key.into_owned(), entry.into_owned());
+                    }
+                    Ok(JournalOp::Remove { key }) => {
+                        index.remove(key.as_ref());
+                    }
+                    // A line cut short by a crash
+                    Err(_) => {}
+                }
+            }
+        }
+
+        let mut state = State::default();
+        for (key, entry) in index {
+            if !state.bodies.contains_key(&entry.body) {
+                let Ok(metadata) = fs::metadata(dir.join(BODIES_DIR).join(&entry.body)) else {
+                    continue;
+                };
+                state.size += metadata.len();
+                let body = StoredBody {
+                    size: metadata.len(),
+                    refs: 0,
+                    bytes: None,
+                };
+                state.bodies.insert(entry.body.clone(), body);
+            }
+            state.bodies.get_mut(&entry.body).unwrap().refs += 1;
+            state.size += metadata_size(&key, &entry);
+            state.clock = state.clock.max(entry.last_used);
+            state.index.insert(key, entry);
+        }
+        for file in fs::read_dir(dir.join(BODIES_DIR))? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            if !state.bodies.contains_key(&name) {
+                let _ = fs::remove_file(file.path());
+            }
+        }
+
+        let cache = LocalCache {
+            max_size,
+            ttl,
+            dir: Some(dir.to_path_buf()),
+            state: Mutex::new(state),
+        };
+        {
+            let mut state = cache.state.lock().unwrap();
+            cache.evict(&mut state);
+            cache.compact(&mut state);
+        }
+        Ok(cache)
+    }
+
+    fn body_path(&self, hash: &str) -> Option<PathBuf> {
+        Some(self.dir.as_ref()?.join(BODIES_DIR).join(hash))
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedPage> {
+        let (cached, size, path) = {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            if state.index.get(key)?.is_expired() {
+                self.remove_entry(&mut state, key);
+                self.maybe_compact(&mut state);
+                return None;
+            }
+            let State { index, bodies, .. } = &mut *state;
+            let entry = index.get_mut(key)?;
+            entry.last_used = clock;
+            if let Some(bytes) = bodies.get(&entry.body).and_then(|body| body.bytes.clone()) {
+                return Some(entry.cached_page(bytes));
+            }
+            let path = self.body_path(&entry.body)?;
+            (entry.cached_page(Bytes::new()), entry.size, path)
+        };
+        // Read outside the lock, a body that went missing is a miss
+        match fs::read(path) {
+            Ok(body) if body.len() as u64 == size => Some(CachedPage {
+                body: Bytes::from(body),
+                ..cached
+            }),
+            _ => {
+                self.remove(key);
+                None
+            }
+        }
+    }
+
+    /// Keeps the page and its body. Pages larger than the whole cache are not kept
+    pub fn insert(&self, key: &str, metadata: Metadata, body: Bytes) {
+        let hash = hash(&body);
+        let entry = IndexEntry {
+            metadata,
+            body: hash.clone(),
+            size: body.len() as u64,
+            last_used: 0,
+            expires: self.ttl.map(|ttl| now_millis() + ttl.as_millis() as u64),
+        };
+        let size = metadata_size(key, &entry);
+        let mut state = self.state.lock().unwrap();
+        // The page replaces what was kept before, even when it is too large to keep
+        self.remove_entry(&mut state, key);
+        if size + body.len() as u64 > self.max_size {
+            self.maybe_compact(&mut state);
+            return;
+        }
+        if let Some(path) = self.body_path(&hash) {
+            // Bodies are written once, pages with the same body share the file
+            if !state.bodies.contains_key(&hash) {
+                if let Err(err) = write_atomic(&path, &body) {
+                    println!("Could not write cache body {}: {err}", path.display());
+                    self.maybe_compact(&mut state);
+                    return;
+                }
+            }
+        }
+        let stored = state.bodies.entry(hash).or_insert_with(|| StoredBody {
+            size: body.len() as u64,
+            refs: 0,
+            bytes: None,
+        });
+        stored.refs += 1;
+        if stored.refs == 1 {
+            if self.dir.is_none() {
+                stored.bytes = Some(body.clone());
+            }
+            state.size += body.len() as u64;
+        }
+        state.clock += 1;
+        state.size += size;
+        let last_used = state.clock;
+        state
+            .index
+            .insert(key.to_string(), IndexEntry { last_used, ..entry });
+        let State { index, journal, .. } = &mut *state;
+        journal.append(&JournalOp::Insert {
+            key: Cow::Borrowed(key),
+            entry: Box::new(Cow::Borrowed(&index[key])),
+        });
+        self.evict(&mut state);
+        self.maybe_compact(&mut state);
+    }
+
+    fn remove(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        if self.remove_entry(&mut state, key) {
+            self.maybe_compact(&mut state);
+        }
+    }
+
+    /// Removes all pages, or those of one URL. Returns the number of removed pages
+    pub fn purge(&self, url: Option<&str>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<String> = state
+            .index
+            .iter()
+            .filter(|(_, entry)| {
+                let metadata = &entry.metadata;
+                url.is_none_or(|url| metadata.url == url || metadata.content.url == url)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            self.remove_entry(&mut state, key);
+        }
+        self.maybe_compact(&mut state);
+        keys.len()
+    }
+
+    fn remove_entry(&self, state: &mut State, key: &str) -> bool {
+        let Some(entry) = state.index.remove(key) else {
+            return false;
+        };
+        state.size -= metadata_size(key, &entry);
+        state.journal.append(&JournalOp::Remove {
+            key: Cow::Borrowed(key),
+        });
+        if let Some(body) = state.bodies.get_mut(&entry.body) {
+            body.refs -= 1;
+            if body.refs == 0 {
+                state.size -= body.size;
+                state.bodies.remove(&entry.body);
+                if let Some(path) = self.body_path(&entry.body) {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+        true
+    }
+
+    /// Drops the least recently used pages until the cache fits in `max_size`
+    fn evict(&self, state: &mut State) {
+        while state.size > self.max_size {
+            let oldest = state
+                .index
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            let Some(oldest) = oldest else {
+                break;
+            };
+            self.remove_entry(state, &oldest);
+        }
+    }
+
+    fn maybe_compact(&self, state: &mut State) {
+        if state.journal.len > COMPACT_AFTER.max(state.index.len()) {
+            self.compact(state);
+        }
+    }
+
+    /// Writes the whole index and starts an empty journal. The journal is only emptied once
+    /// the index is written, so a crash in between loses nothing
+    fn compact(&self, state: &mut State) {
+        let Some(ref dir) = self.dir else {
+            return;
+        };
+        let path = dir.join(INDEX_FILE);
+        let index = serde_json::to_vec(&state.index).unwrap();
+        if let Err(err) = write_atomic(&path, &index) {
+            println!("Could not write cache index {}: {err}", path.display());
+            return;
+        }
+        let path = dir.join(JOURNAL_FILE);
+        match File::create(&path) {
+            Ok(file) => {
+                state.journal = Journal {
+                    file: Some(file),
+                    len: 0,
+                }
+            }
+            Err(err) => println!("Could not write cache journal {}: {err}", path.display()),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            backend: if self.dir.is_some() { "disk" } else { "memory" },
+            entries: Some(state.index.len()),
+            bodies: Some(state.bodies.len()),
+            size: Some(state.size),
+            dir: self.dir.clone(),
+            ..CacheStats::default()
+        }
+    }
+}
+
+impl LocalCache {
+    /// Runs `f` on the blocking threads when the cache is on disk, right away in memory
+    async fn run<T, F>(self: &Arc<Self>, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&LocalCache) -> T + Send + 'static,
+    {
+        if self.dir.is_none() {
+            return f(self);
+        }
+        let cache = Arc::clone(self);
+        tokio::task::spawn_blocking(move || f(&cache))
+            .await
+            .unwrap()
+    }
+}
+
+impl CacheBackend for Arc<LocalCache> {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<CachedPage>> {
+        let key = key.to_string();
+        Box::pin(self.run(move |cache| cache.get(&key)))
+    }
+
+    fn insert<'a>(
+        &'a self,
+        key: &'a str,
+        url: &'a str,
+        content: &'a PageContent,
+        body: Bytes,
+    ) -> BoxFuture<'a, ()> {
+        let (key, metadata) = (key.to_string(), Metadata::new(url, content));
+        Box::pin(self.run(move |cache| cache.insert(&key, metadata, body)))
+    }
+
+    fn purge<'a>(&'a self, url: Option<&'a str>) -> BoxFuture<'a, usize> {
+        let url = url.map(String::from);
+        Box::pin(self.run(move |cache| cache.purge(url.as_deref())))
+    }
+
+    fn stats(&self) -> BoxFuture<'_, CacheStats> {
+        Box::pin(async move { LocalCache::stats(self) })
+    }
+}
+
+/// Upstream pages that are revalidated with a conditional request instead of fetched again,
+/// kept by a backend. Counts hits and misses
+pub struct Cache {
+    backend: Box<dyn CacheBackend>,
+    /// Streamed bodies larger than this are not kept
+    max_size: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(backend: Box<dyn CacheBackend>, max_size: u64) -> Self {
+        Cache {
+            backend,
+            max_size,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Keys are hashed before they reach the backend, as they hold the request headers,
+    /// like `Authorization` or `Cookie`
+    pub async fn get(&self, key: &str) -> Option<CachedPage> {
+        self.backend.get(&hash(key.as_bytes())).await
+    }
+
+    pub async fn insert(&self, key: &str, url: &str, content: &PageContent, body: Bytes) {
+        let key = hash(key.as_bytes());
+        self.backend.insert(&key, url, content, body).await
+    }
+
+    /// Removes all pages, or those of one URL. Returns the number of removed pages
+    pub async fn purge(&self, url: Option<&str>) -> usize {
+        self.backend.purge(url).await
+    }
+
+    /// Counts a `HIT` or `MISS`
+    pub fn record(&self, outcome: &'static str) {
+        let counter = if outcome == HIT {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        CacheStats {
+            max_size: self.max_size,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ..self.backend.stats().await
+        }
+    }
+
+    /// Relays the body and keeps the page once the whole body went through.
+    /// A body that fails or grows larger than the cache is not kept
+    pub fn keep_streamed<S, E>(
+        &'static self,
+        chunks: S,
+        key: String,
+        url: String,
+        content: PageContent,
+    ) -> impl Stream<Item = Result<Bytes, E>> + Send
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Unpin,
+        E: Send,
+    {
+        let max_size = self.max_size as usize;
+        let page = (key, url, content);
+        stream::unfold(
+            Some((chunks, Some(Vec::new()), page)),
+            move |state| async move {
+                let (mut chunks, mut body, page) = state?;
+                match chunks.next().await {
+                    Some(Ok(chunk)) => {
+                        body = body.filter(|body| body.len() + chunk.len() <= max_size);
+                        if let Some(ref mut body) = body {
+                            body.extend_from_slice(&chunk);
+                        }
+                        Some((Ok(chunk), Some((chunks, body, page))))
+                    }
+                    Some(Err(err)) => Some((Err(err), None)),
+                    None => {
+                        if let Some(body) = body {
+                            let (key, url, content) = page;
+                            self.insert(&key, &url, &content, Bytes::from(body)).await;
+                        }
+                        None
+                    }
+                }
+            },
+        )
+    }
+}
+
+/// The backend of the cache: Redis when `cache_redis_url` is set, shared by all replicas,
+/// otherwise `cache_dir` or memory
+fn backend() -> Option<Box<dyn CacheBackend>> {
+    let config = config();
+    if let Some(ref url) = config.cache_redis_url {
+        return match RedisCache::new(url, config.cache_ttl) {
+            Ok(redis) => Some(Box::new(redis)),
+            Err(err) => {
+                println!("Invalid cache Redis URL: {err}");
+                None
+            }
+        };
+    }
+    let Some(ref dir) = config.cache_dir else {
+        let memory = LocalCache::in_memory(config.cache_max_size, config.cache_ttl);
+        return Some(Box::new(Arc::new(memory)));
+    };
+    match LocalCache::open(dir, config.cache_max_size, config.cache_ttl) {
+        Ok(cache) => Some(Box::new(Arc::new(cache))),
+        Err(err) => {
+            println!("Could not open cache {}: {err}", dir.display());
+            None
+        }
+    }
+}
+
+/// The cache of the running service, none when `cache_max_size` is zero
+pub fn cache() -> Option<&'static Cache> {
+    static CACHE: OnceLock<Option<Cache>> = OnceLock::new();
+    CACHE
+        .get_or_init(|| {
+            let max_size = config().cache_max_size;
+            if max_size == 0 {
+                return None;
+            }
+            backend().map(|backend| Cache::new(backend, max_size))
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(etag: &str) -> PageContent {
+        let mut content = PageContent::invalid(String::new(), "https://a.test/".to_string());
+        content.error = None;
+        content.http_code = Some(200);
+        content.content_type = Some("text/plain".to_string());
+        content.upstream_etag = Some(etag.to_string());
+        content
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn insert(cache: &LocalCache, key: &str, body: &'static str) {
+        let body = Bytes::from_static(body.as_bytes());
+        let metadata = Metadata::new("https://a.test/", &page("\"1\""));
+        cache.insert(key, metadata, body);
+    }
+
+    #[test]
+    fn least_recently_used_page_should_be_evicted() {
+        // Each page takes 1 (key) + 2 * 15 (urls) + 128 bytes of metadata, and its body
+        let cache = LocalCache::in_memory(3 * 159 + 30, None);
+        insert(&cache, "a", "aaaaaaaaaa");
+        insert(&cache, "b", "bbbbbbbbbb");
+        insert(&cache, "c", "cccccccccc");
+        assert!(cache.get("a").is_some());
+
+        insert(&cache, "d", "dddddddddd");
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert!(cache.get("d").is_some());
+    }
+
+    #[test]
+    fn identical_bodies_should_be_stored_once() {
+        let cache = LocalCache::in_memory(10_000, None);
+        insert(&cache, "a", "same body");
+        insert(&cache, "b", "same body");
+        assert_eq!(cache.stats().entries, Some(2));
+        assert_eq!(cache.stats().bodies, Some(1));
+
+        assert_eq!(cache.purge(Some("https://a.test/")), 2);
+        assert_eq!(cache.stats().bodies, Some(0));
+        assert_eq!(cache.stats().size, Some(0));
+    }
+
+    #[test]
+    fn page_larger_than_cache_should_not_be_kept() {
+        let cache = LocalCache::in_memory(200, None);
+        insert(&cache, "a", "a");
+        insert(
+            &cache,
+            "a",
+            "much too large for this cache, which only holds small pages",
+        );
+
+        assert!(cache.get("a").is_none());
+        assert!(is_revalidatable(&page("\"1\"")));
+        assert!(!is_revalidatable(&PageContent::invalid(
+            String::new(),
+            "u".to_string()
+        )));
+    }
+
+    #[test]
+    fn disk_cache_should_survive_reopening() {
+        let dir = dir("reopen");
+        {
+            let cache = LocalCache::open(&dir, 10_000, None).unwrap();
+            insert(&cache, "a", "kept body");
+            insert(&cache, "b", "other body");
+            cache.purge(None);
+            insert(&cache, "a", "kept body");
+        }
+        fs::write(dir.join(BODIES_DIR).join("orphan"), "left by a crash").unwrap();
+        let mut journal = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(JOURNAL_FILE))
+            .unwrap();
+        journal.write_all(b"{\"insert\":{\"key\":\"c").unwrap();
+
+        let cache = LocalCache::open(&dir, 10_000, None).unwrap();
+        let cached = cache.get("a").unwrap();
+        assert_eq!(cached.body.as_ref(), b"kept body");
+        assert_eq!(cached.content.upstream_etag.as_deref(), Some("\"1\""));
+        assert_eq!(cached.page_content().contents.as_deref(), Some("kept body"));
+        assert!(cache.get("b").is_none());
+        assert_eq!(fs::read_dir(dir.join(BODIES_DIR)).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn journal_of_replaced_pages_should_be_compacted() {
+        let dir = dir("compact");
+        {
+            let cache = LocalCache::open(&dir, 10_000_000, None).unwrap();
+            for i in 0..COMPACT_AFTER + 10 {
+                insert(&cache, &(i % 10).to_string(), "body");
+            }
+        }
+        let journal = fs::read_to_string(dir.join(JOURNAL_FILE)).unwrap();
+        assert!(journal.lines().count() < COMPACT_AFTER);
+
+        let cache = LocalCache::open(&dir, 10_000_000, None).unwrap();
+        assert_eq!(cache.stats().entries, Some(10));
+        assert_eq!(fs::read(dir.join(JOURNAL_FILE)).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn disk_cache_should_be_used_as_backend() {
+        let dir = dir("backend");
+        let disk = LocalCache::open(&dir, 10_000, None).unwrap();
+        let cache = Cache::new(Box::new(Arc::new(disk)), 10_000);
+        cache
+            .insert("a", "u", &page("\"1\""), Bytes::from("on disk"))
+            .await;
+
+        let cached = cache.get("a").await.unwrap();
+        assert_eq!(cached.body.as_ref(), b"on disk");
+        assert_eq!(cache.purge(Some("u")).await, 1);
+        assert_eq!(cache.stats().await.backend, "disk");
+    }
+
+    #[tokio::test]
+    async fn request_headers_in_key_should_not_be_stored() {
+        let dir = dir("hashed");
+        let disk = LocalCache::open(&dir, 10_000, None).unwrap();
+        let cache = Cache::new(Box::new(Arc::new(disk)), 10_000);
+        let key = "GET u false authorization:\"Bearer secret\"";
+        cache
+            .insert(key, "u", &page("\"1\""), Bytes::from("private"))
+            .await;
+
+        assert!(cache.get(key).await.is_some());
+        let journal = fs::read_to_string(dir.join(JOURNAL_FILE)).unwrap();
+        assert!(!journal.contains("secret"));
+    }
+
+    #[test]
+    fn missing_body_file_should_be_a_miss() {
+        let dir = dir("missing");
+        let cache = LocalCache::open(&dir, 10_000, None).unwrap();
+        insert(&cache, "a", "body");
+        for file in fs::read_dir(dir.join(BODIES_DIR)).unwrap() {
+            fs::remove_file(file.unwrap().path()).unwrap();
+        }
+
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.stats().entries, Some(0));
+    }
+
+    #[tokio::test]
+    async fn streamed_body_should_be_kept_when_complete() {
+        let memory = LocalCache::in_memory(10_000, None);
+        let cache: &'static Cache =
+            Box::leak(Box::new(Cache::new(Box::new(Arc::new(memory)), 10_000)));
+        let chunks = stream::iter(
+            ["streamed ", "body"]
+                .map(|chunk| Ok::<_, io::Error>(Bytes::from_static(chunk.as_bytes()))),
+        );
+
+        let relayed = cache.keep_streamed(chunks, "a".to_string(), "u".to_string(), page("\"1\""));
+        let relayed: Vec<_> = Box::pin(relayed).collect().await;
+
+        assert_eq!(relayed.len(), 2);
+        let cached = cache.get("a").await.unwrap();
+        assert_eq!(cached.body.as_ref(), b"streamed body");
+        assert_eq!(cache.stats().await.backend, "memory");
+    }
+
+    #[test]
+    fn expired_page_should_be_a_miss() {
+        let cache = LocalCache::in_memory(10_000, Some(Duration::from_millis(20)));
+        insert(&cache, "a", "short lived");
+        assert!(cache.get("a").is_some());
+
+        std::thread::sleep(Duration::from_millis(30));
+
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.stats().entries, Some(0));
+    }
+}
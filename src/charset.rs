@@ -0,0 +1,144 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+
+/// How far into an HTML page a `<meta charset>` is looked for, as browsers do
+pub(crate) const META_PRESCAN: usize = 1024;
+
+/// The encoding for a charset label, like `utf-8`, `latin1` or `shift_jis`
+pub fn encoding_for(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+}
+
+/// The charset param of a content type
+fn header_charset(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("charset") {
+            return None;
+        }
+        encoding_for(value.trim().trim_matches(['"', '\'']))
+    })
+}
+
+/// The charset of `<meta charset="..">` or `<meta http-equiv="Content-Type" content="..">`
+/// at the start of the page
+fn meta_charset(body: &[u8]) -> Option<&'static Encoding> {
+    let start = String::from_utf8_lossy(&body[..body.len().min(META_PRESCAN)]).to_lowercase();
+    start.match_indices("<meta").find_map(|(index, _)| {
+        let tag = &start[index..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        let value = &tag[tag.find("charset")? + "charset".len()..];
+        let value = value.trim_start().strip_prefix('=')?.trim_start();
+        let value = value.trim_start_matches(['"', '\'']);
+        let end = value
+            .find(|c: char| c == '"' || c == '\'' || c == ';' || c == '/' || c.is_whitespace())
+            .unwrap_or(value.len());
+        let encoding = encoding_for(&value[..end])?;
+        // A page that could be read this far is not UTF-16
+        Some(match encoding {
+            encoding if encoding == UTF_16LE || encoding == UTF_16BE => UTF_8,
+            encoding if encoding == X_USER_DEFINED => WINDOWS_1252,
+            encoding => encoding,
+        })
+    })
+}
+
+fn is_html(content_type: Option<&str>) -> bool {
+    content_type.is_none_or(|content_type| content_type.to_ascii_lowercase().contains("html"))
+}
+
+/// The encoding of a body: a byte order mark wins, then the charset of the content type,
+/// then a `<meta>` charset of HTML pages. UTF-8 when none is found
+pub fn detect(content_type: Option<&str>, body: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(body) {
+        return encoding;
+    }
+    content_type
+        .and_then(header_charset)
+        .or_else(|| {
+            if is_html(content_type) {
+                meta_charset(body)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(UTF_8)
+}
+
+/// The body as text, with the encoding it was decoded from. Invalid bytes are replaced
+pub fn decode(content_type: Option<&str>, body: &[u8]) -> (String, &'static Encoding) {
+    let (text, encoding, _) = detect(content_type, body).decode(body);
+    (text.into_owned(), encoding)
+}
+
+/// The content type with its charset param set to `charset`
+pub fn with_charset(content_type: &str, charset: &str) -> String {
+    let mut params = content_type.split(';');
+    let mut with_charset = params.next().unwrap_or_default().trim().to_string();
+    for param in params {
+        let is_charset = param
+            .split_once('=')
+            .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("charset"));
+        if !is_charset && !param.trim().is_empty() {
+            with_charset.push_str(&format!("; {}", param.trim()));
+        }
+    }
+    with_charset.push_str(&format!("; charset={charset}"));
+    with_charset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1251};
+
+    #[test]
+    fn charset_should_be_detected_in_order() {
+        let latin = b"caf\xe9";
+        assert_eq!(
+            detect(Some("text/plain; charset=ISO-8859-1"), latin),
+            WINDOWS_1252
+        );
+        assert_eq!(detect(Some("text/plain"), latin), UTF_8);
+
+        let bom = b"\xef\xbb\xbfcaf\xc3\xa9";
+        assert_eq!(detect(Some("text/plain; charset=latin1"), bom), UTF_8);
+
+        let html = br#"<html><head><meta charset="windows-1251"></head>"#;
+        assert_eq!(detect(Some("text/html"), html), WINDOWS_1251);
+        assert_eq!(detect(None, html), WINDOWS_1251);
+        assert_eq!(detect(Some("text/html; charset=utf-8"), html), UTF_8);
+        assert_eq!(detect(Some("text/plain"), html), UTF_8);
+    }
+
+    #[test]
+    fn http_equiv_meta_should_be_detected() {
+        let html = br#"<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=Shift_JIS">"#;
+        assert_eq!(detect(Some("text/html"), html), SHIFT_JIS);
+
+        let utf16 = br#"<meta charset="utf-16">"#;
+        assert_eq!(detect(Some("text/html"), utf16), UTF_8);
+    }
+
+    #[test]
+    fn body_should_be_decoded() {
+        let (text, encoding) = decode(Some("text/html; charset=windows-1252"), b"caf\xe9 \x80");
+        assert_eq!(text, "café €");
+        assert_eq!(encoding.name(), "windows-1252");
+
+        let (text, encoding) = decode(None, b"\xff\xfeh\0i\0");
+        assert_eq!(text, "hi");
+        assert_eq!(encoding, UTF_16LE);
+    }
+
+    #[test]
+    fn charset_should_replace_content_type_charset() {
+        assert_eq!(
+            with_charset("text/html; charset=iso-8859-1", "UTF-8"),
+            "text/html; charset=UTF-8"
+        );
+        assert_eq!(
+            with_charset("text/plain;format=flowed", "Shift_JIS"),
+            "text/plain; format=flowed; charset=Shift_JIS"
+        );
+    }
+}
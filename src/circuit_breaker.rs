@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use url::Url;
+
+use crate::config::config;
+
+/// The error in `PageContent` when a request is not sent because the circuit is open
+pub const CIRCUIT_OPEN: &str = "circuit_open";
+
+/// When a circuit opens and for how long
+pub struct Settings {
+    /// Share of failed requests in the window that opens the circuit
+    pub failure_rate: f64,
+    /// Fewer requests than this in the window never open the circuit
+    pub min_requests: u32,
+    /// Requests are counted over this long. Closed circuits without requests for this long
+    /// are forgotten
+    pub window: Duration,
+    /// How long the circuit stays open before a probe request is let through
+    pub open_duration: Duration,
+}
+
+enum State {
+    Closed {
+        since: Instant,
+        requests: u32,
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        probe_started: Instant,
+    },
+}
+
+impl State {
+    /// A closed circuit whose window has passed is the same as no circuit
+    fn is_idle(&self, now: Instant, window: Duration) -> bool {
+        matches!(self, State::Closed { since, .. } if now.duration_since(*since) > window)
+    }
+}
+
+struct Hosts {
+    states: HashMap<String, State>,
+    pruned: Instant,
+}
+
+/// Circuit breaker per upstream host. Requests to a host that keeps failing fail fast,
+/// until a probe request succeeds
+pub struct CircuitBreaker {
+    settings: Settings,
+    hosts: Mutex<Hosts>,
+}
+
+/// The state of one circuit, for the admin endpoint
+#[derive(Serialize)]
+pub struct CircuitStatus {
+    pub state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failures: Option<u32>,
+    /// Time left until a probe request is let through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in_ms: Option<u128>,
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: Settings) -> Self {
+        CircuitBreaker {
+            settings,
+            hosts: Mutex::new(Hosts {
+                states: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Whether a request to the host may be sent. A half open circuit lets one probe through
+    pub fn allow(&self, host: &str) -> bool {
+        let now = Instant::now();
+        let hosts = &mut self.hosts.lock().unwrap().states;
+        match hosts.get(host) {
+            Some(State::Open { until }) if now < *until => false,
+            Some(State::HalfOpen { probe_started })
+                if now.duration_since(*probe_started) < self.settings.open_duration =>
+            {
+                false
+            }
+            Some(State::Open { .. }) | Some(State::HalfOpen { .. }) => {
+                hosts.insert(host.to_string(), State::HalfOpen { probe_started: now });
+                true
+            }
+            Some(State::Closed { .. }) | None => true,
+        }
+    }
+
+    /// Records the outcome of a request that was sent to the host
+    pub fn record(&self, host: &str, success: bool) {
+        let now = Instant::now();
+        let settings = &self.settings;
+        let mut hosts = self.hosts.lock().unwrap();
+        if now.duration_since(hosts.pruned) > settings.window {
+            hosts
+                .states
+                .retain(|_, state| !state.is_idle(now, settings.window));
+            hosts.pruned = now;
+        }
+        let state = hosts
+            .states
+            .entry(host.to_string())
+            .or_insert(State::Closed {
+                since: now,
+                requests: 0,
+                failures: 0,
+            });
+        let next = match state {
+            State::HalfOpen { .. } | State::Open { .. } if success => State::Closed {
+                since: now,
+                requests: 0,
+                failures: 0,
+            },
+            State::HalfOpen { .. } | State::Open { .. } => State::Open {
+                until: now + settings.open_duration,
+            },
+            State::Closed {
+                since,
+                requests,
+                failures,
+            } => {
+                let (since, requests, failures) = if now.duration_since(*since) > settings.window {
+                    (now, 0, 0)
+                } else {
+                    (*since, *requests, *failures)
+                };
+                let (requests, failures) = (requests + 1, failures + u32::from(!success));
+                if requests >= settings.min_requests
+                    && f64::from(failures) / f64::from(requests) >= settings.failure_rate
+                {
+                    println!("Circuit opened for {host}, {failures} of {requests} requests failed");
+                    State::Open {
+                        until: now + settings.open_duration,
+                    }
+                } else {
+                    State::Closed {
+                        since,
+                        requests,
+                        failures,
+                    }
+                }
+            }
+        };
+        *state = next;
+    }
+
+    /// The state of all known circuits
+    pub fn status(&self) -> BTreeMap<String, CircuitStatus> {
+        let now = Instant::now();
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .states
+            .iter()
+            .filter(|(_, state)| !state.is_idle(now, self.settings.window))
+            .map(|(host, state)| {
+                let status = match state {
+                    State::Closed {
+                        requests, failures, ..
+                    } => CircuitStatus {
+                        state: "closed",
+                        requests: Some(*requests),
+                        failures: Some(*failures),
+                        retry_in_ms: None,
+                    },
+                    State::Open { until } => CircuitStatus {
+                        state: "open",
+                        requests: None,
+                        failures: None,
+                        retry_in_ms: Some(until.saturating_duration_since(now).as_millis()),
+                    },
+                    State::HalfOpen { .. } => CircuitStatus {
+                        state: "half_open",
+                        requests: None,
+                        failures: None,
+                        retry_in_ms: None,
+                    },
+                };
+                (host.clone(), status)
+            })
+            .collect()
+    }
+}
+
+/// The circuit of a URL is per host and port, none when the circuit breaker is disabled
+pub fn circuit_key(url: &str) -> Option<String> {
+    if !config().circuit_breaker {
+        return None;
+    }
+    let url = Url::parse(url).ok()?;
+    Some(format!(
+        "{}:{}",
+        url.host_str()?,
+        url.port_or_known_default()?
+    ))
+}
+
+/// The circuit breaker of the running service
+pub fn circuit_breaker() -> &'static CircuitBreaker {
+    static CIRCUIT_BREAKER: OnceLock<CircuitBreaker> = OnceLock::new();
+    CIRCUIT_BREAKER.get_or_init(|| {
+        let config = config();
+        CircuitBreaker::new(Settings {
+            failure_rate: config.circuit_failure_rate,
+            min_requests: config.circuit_min_requests,
+            window: config.circuit_window,
+            open_duration: config.circuit_open_duration,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(open_duration: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(Settings {
+            failure_rate: 0.5,
+            min_requests: 4,
+            window: Duration::from_secs(60),
+            open_duration,
+        })
+    }
+
+    #[test]
+    fn circuit_should_open_after_failure_rate() {
+        let breaker = breaker(Duration::from_secs(60));
+        breaker.record("a", true);
+        breaker.record("a", false);
+        breaker.record("a", true);
+        assert!(breaker.allow("a"));
+        assert_eq!(breaker.status()["a"].state, "closed");
+
+        breaker.record("a", false);
+        assert!(!breaker.allow("a"));
+        assert_eq!(breaker.status()["a"].state, "open");
+        assert!(breaker.allow("b"));
+    }
+
+    #[test]
+    fn half_open_circuit_should_let_one_probe_through() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..4 {
+            breaker.record("a", false);
+        }
+        assert_eq!(breaker.status()["a"].state, "open");
+
+        assert!(breaker.allow("a"));
+        assert_eq!(breaker.status()["a"].state, "half_open");
+        breaker.record("a", false);
+        assert_eq!(breaker.status()["a"].state, "open");
+
+        assert!(breaker.allow("a"));
+        breaker.record("a", true);
+        assert_eq!(breaker.status()["a"].state, "closed");
+        assert_eq!(breaker.status()["a"].requests, Some(0));
+    }
+
+    #[test]
+    fn idle_closed_circuits_should_be_forgotten() {
+        let breaker = CircuitBreaker::new(Settings {
+            window: Duration::from_millis(20),
+            ..breaker(Duration::from_secs(60)).settings
+        });
+        breaker.record("idle", true);
+        for _ in 0..4 {
+            breaker.record("open", false);
+        }
+        std::thread::sleep(Duration::from_millis(30));
+
+        breaker.record("active", true);
+        let hosts = breaker.hosts.lock().unwrap();
+        assert!(!hosts.states.contains_key("idle"));
+        assert!(hosts.states.contains_key("open"));
+        assert!(hosts.states.contains_key("active"));
+    }
+
+    #[test]
+    fn probe_in_flight_should_block_other_requests() {
+        let breaker = breaker(Duration::from_millis(50));
+        for _ in 0..4 {
+            breaker.record("a", false);
+        }
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert!(breaker.allow("a"));
+        assert!(!breaker.allow("a"));
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use futures_util::future::{BoxFuture, Shared};
+use futures_util::FutureExt;
+
+use crate::page_types::PageContent;
+
+type InFlight = HashMap<String, (u64, Shared<BoxFuture<'static, PageContent>>)>;
+
+fn in_flight() -> &'static Mutex<InFlight> {
+    static IN_FLIGHT: OnceLock<Mutex<InFlight>> = OnceLock::new();
+    IN_FLIGHT.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Runs `fetch`, unless a fetch with the same key is already running. Then its result is
+/// shared instead
+pub async fn coalesce<F>(key: String, fetch: F) -> PageContent
+where
+    F: Future<Output = PageContent> + Send + 'static,
+{
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let shared = {
+        let mut running = in_flight().lock().unwrap();
+        match running.get(&key) {
+            Some((_, shared)) => shared.clone(),
+            None => {
+                let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                let remove_key = key.clone();
+                let shared = async move {
+                    let content = fetch.await;
+                    let mut in_flight = in_flight().lock().unwrap();
+                    if in_flight.get(&remove_key).is_some_and(|(i, _)| *i == id) {
+                        in_flight.remove(&remove_key);
+                    }
+                    content
+                }
+                .boxed()
+                .shared();
+                running.insert(key, (id, shared.clone()));
+                shared
+            }
+        }
+    };
+    shared.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn concurrent_fetches_should_share_one_result() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let fetch = |calls: Arc<AtomicUsize>| async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            PageContent::invalid("result".to_string(), "url".to_string())
+        };
+
+        let results = futures_util::future::join_all(
+            (0..5).map(|_| coalesce("test-shared".to_string(), fetch(calls.clone()))),
+        )
+        .await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|r| r.error.as_deref() == Some("result")));
+
+        coalesce("test-shared".to_string(), fetch(calls.clone())).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
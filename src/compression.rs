@@ -0,0 +1,252 @@
+use std::io;
+use std::str::FromStr;
+
+use async_compression::tokio::write::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use futures_util::{stream, StreamExt};
+use serde::{Serialize, Serializer};
+use tokio::io::AsyncWriteExt;
+use warp::http::{header, HeaderValue, StatusCode};
+use warp::hyper::body::{Bytes, HttpBody};
+use warp::hyper::Body;
+use warp::reply::Response;
+
+use crate::config::config;
+
+/// Supported content encodings for responses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+impl Serialize for Encoding {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "br" => Ok(Encoding::Brotli),
+            "zstd" => Ok(Encoding::Zstd),
+            "gzip" => Ok(Encoding::Gzip),
+            _ => Err(format!("Unsupported encoding {s}")),
+        }
+    }
+}
+
+/// Compresses the response if the client accepts one of the enabled encodings and the
+/// content type and size are worth it. The body is compressed while it is streamed
+pub fn compress(mut response: Response, accept_encoding: Option<String>) -> Response {
+    let config = config();
+    if !is_compressible(&response, &config.compression_content_types) {
+        return response;
+    }
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+    let too_small = content_length(&response).is_some_and(|len| len < config.compression_min_size);
+    let encoding =
+        accept_encoding.and_then(|accept| negotiate(&accept, &config.compression_encodings));
+    let Some(encoding) = encoding.filter(|_| !too_small) else {
+        return response;
+    };
+
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_LENGTH);
+    // The compressed bytes differ, but the contents are the same
+    let strong_etag = headers
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .and_then(|etag| HeaderValue::from_str(&format!("W/{etag}")).ok());
+    if let Some(weak_etag) = strong_etag {
+        headers.insert(header::ETAG, weak_etag);
+    }
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    let body = std::mem::take(response.body_mut());
+    *response.body_mut() = compress_body(body, encoding);
+    response
+}
+
+fn is_compressible(response: &Response, content_types: &[String]) -> bool {
+    let headers = response.headers();
+    if headers.contains_key(header::CONTENT_ENCODING)
+        || matches!(
+            response.status(),
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED | StatusCode::PARTIAL_CONTENT
+        )
+    {
+        return false;
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    content_types
+        .iter()
+        .any(|prefix| content_type.starts_with(prefix.as_str()))
+}
+
+/// The size of the body, if it is known before streaming it
+fn content_length(response: &Response) -> Option<u64> {
+    response.body().size_hint().exact().or_else(|| {
+        response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok())
+    })
+}
+
+/// The best encoding in `Accept-Encoding`, by quality and then by the order of `enabled`
+pub fn negotiate(accept_encoding: &str, enabled: &[Encoding]) -> Option<Encoding> {
+    let accepted: Vec<(&str, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let name = params.next()?.trim();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            Some((name, quality))
+        })
+        .collect();
+
+    let quality = |encoding: &Encoding| {
+        accepted
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.as_str()))
+            .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0)
+    };
+    enabled
+        .iter()
+        .map(|encoding| (*encoding, quality(encoding)))
+        .filter(|(_, quality)| *quality > 0.0)
+        .fold(
+            None,
+            |best: Option<(Encoding, f32)>, candidate| match best {
+                Some(best) if best.1 >= candidate.1 => Some(best),
+                _ => Some(candidate),
+            },
+        )
+        .map(|(encoding, _)| encoding)
+}
+
+/// Every chunk of the body is compressed and flushed, so streamed responses keep streaming
+fn compress_body(body: Body, encoding: Encoding) -> Body {
+    let encoder = Encoder::new(encoding);
+    let chunks = stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                let compressed = encoder.write(&chunk).await;
+                Some((compressed, Some((body, encoder))))
+            }
+            Some(Err(err)) => Some((Err(io::Error::other(err)), None)),
+            None => Some((encoder.finish().await, None)),
+        }
+    });
+    Body::wrap_stream(chunks.filter(|chunk| {
+        let empty = matches!(chunk, Ok(bytes) if bytes.is_empty());
+        async move { !empty }
+    }))
+}
+
+enum Encoder {
+    Brotli(Box<BrotliEncoder<Vec<u8>>>),
+    Zstd(ZstdEncoder<Vec<u8>>),
+    Gzip(GzipEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(BrotliEncoder::new(Vec::new()))),
+            Encoding::Zstd => Encoder::Zstd(ZstdEncoder::new(Vec::new())),
+            Encoding::Gzip => Encoder::Gzip(GzipEncoder::new(Vec::new())),
+        }
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        match self {
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(chunk).await?;
+                encoder.flush().await?;
+                Ok(std::mem::take(encoder.get_mut()).into())
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(chunk).await?;
+                encoder.flush().await?;
+                Ok(std::mem::take(encoder.get_mut()).into())
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk).await?;
+                encoder.flush().await?;
+                Ok(std::mem::take(encoder.get_mut()).into())
+            }
+        }
+    }
+
+    async fn finish(&mut self) -> io::Result<Bytes> {
+        match self {
+            Encoder::Brotli(encoder) => {
+                encoder.shutdown().await?;
+                Ok(std::mem::take(encoder.get_mut()).into())
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.shutdown().await?;
+                Ok(std::mem::take(encoder.get_mut()).into())
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.shutdown().await?;
+                Ok(std::mem::take(encoder.get_mut()).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    #[test]
+    fn negotiate_should_prefer_quality_then_server_order() {
+        assert_eq!(negotiate("gzip, br", &ALL), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1, br;q=0.5", &ALL), Some(Encoding::Gzip));
+        assert_eq!(negotiate("zstd, gzip", &ALL), Some(Encoding::Zstd));
+        assert_eq!(negotiate("*", &ALL), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*, br;q=0", &ALL), Some(Encoding::Zstd));
+    }
+
+    #[test]
+    fn negotiate_should_ignore_unsupported() {
+        assert_eq!(negotiate("deflate, identity", &ALL), None);
+        assert_eq!(negotiate("gzip;q=0", &ALL), None);
+        assert_eq!(negotiate("br", &[Encoding::Gzip]), None);
+        assert_eq!(negotiate("", &ALL), None);
+    }
+}
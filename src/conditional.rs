@@ -0,0 +1,158 @@
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+use warp::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use warp::hyper::Body;
+use warp::reply::Response;
+
+/// Headers of a response that are kept when it becomes a 304
+const NOT_MODIFIED_HEADERS: [header::HeaderName; 6] = [
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+    header::EXPIRES,
+    header::VARY,
+    header::CONTENT_LOCATION,
+];
+
+/// An entity tag for the bytes. Weak tags are for bodies that change in ways that do not
+/// matter, like the response time in JSON
+pub fn etag(bytes: &[u8], weak: bool) -> String {
+    let hash = Sha256::digest(bytes);
+    let hash: String = hash[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    if weak {
+        format!("W/\"{hash}\"")
+    } else {
+        format!("\"{hash}\"")
+    }
+}
+
+/// Sets the `ETag` and `Last-Modified` of a response
+pub fn with_validators(
+    mut response: Response,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> Response {
+    let headers = response.headers_mut();
+    let validators = [(header::ETAG, etag), (header::LAST_MODIFIED, last_modified)];
+    for (name, value) in validators {
+        if let Some(value) = value.and_then(|value| HeaderValue::from_str(value).ok()) {
+            headers.insert(name, value);
+        }
+    }
+    response
+}
+
+/// Weak comparison, `W/"a"` matches `"a"`
+fn same_tag(a: &str, b: &str) -> bool {
+    a.trim().trim_start_matches("W/") == b.trim().trim_start_matches("W/")
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn http_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(header_str(headers, name)?).ok()
+}
+
+/// Whether the client already has the response, per its `If-None-Match` or, without that,
+/// its `If-Modified-Since`
+fn is_not_modified(request: &HeaderMap, response: &HeaderMap) -> bool {
+    if let Some(if_none_match) = header_str(request, header::IF_NONE_MATCH) {
+        let Some(etag) = header_str(response, header::ETAG) else {
+            return false;
+        };
+        return if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || same_tag(tag, etag));
+    }
+    match (
+        http_date(request, header::IF_MODIFIED_SINCE),
+        http_date(response, header::LAST_MODIFIED),
+    ) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Turns a successful GET or HEAD response into a 304 when the client's copy is still valid
+pub fn conditional(method: &Method, request: &HeaderMap, mut response: Response) -> Response {
+    let safe = method == Method::GET || method == Method::HEAD;
+    if !safe || response.status() != StatusCode::OK || !is_not_modified(request, response.headers())
+    {
+        return response;
+    }
+    *response.status_mut() = StatusCode::NOT_MODIFIED;
+    *response.body_mut() = Body::empty();
+    let mut kept = HeaderMap::new();
+    for name in NOT_MODIFIED_HEADERS {
+        for value in response.headers().get_all(&name) {
+            kept.append(name.clone(), value.clone());
+        }
+    }
+    *response.headers_mut() = kept;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(etag: Option<&str>, last_modified: Option<&str>) -> Response {
+        let mut response = Response::new(Body::from("body"));
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        with_validators(response, etag, last_modified)
+    }
+
+    fn request(name: header::HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn matching_etag_should_be_not_modified() {
+        let tag = etag(b"body", false);
+        assert_eq!(tag, "\"230d8358dc8e8890b4c58deeb62912ee\"");
+        let weak = etag(b"body", true);
+        let matching = request(
+            header::IF_NONE_MATCH,
+            "\"other\", W/\"230d8358dc8e8890b4c58deeb62912ee\"",
+        );
+
+        let not_modified = conditional(&Method::GET, &matching, response(Some(&tag), None));
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(not_modified.headers()[header::ETAG], tag.as_str());
+        assert!(!not_modified.headers().contains_key(header::CONTENT_TYPE));
+
+        let modified = conditional(
+            &Method::GET,
+            &request(header::IF_NONE_MATCH, "\"other\""),
+            response(Some(&weak), None),
+        );
+        assert_eq!(modified.status(), StatusCode::OK);
+
+        let post = conditional(&Method::POST, &matching, response(Some(&tag), None));
+        assert_eq!(post.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn if_modified_since_should_compare_dates() {
+        let modified = Some("Wed, 21 Oct 2015 07:28:00 GMT");
+        let since = request(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT");
+        let before = request(header::IF_MODIFIED_SINCE, "Tue, 20 Oct 2015 07:28:00 GMT");
+
+        let response_since = conditional(&Method::GET, &since, response(None, modified));
+        assert_eq!(response_since.status(), StatusCode::NOT_MODIFIED);
+        let response_before = conditional(&Method::GET, &before, response(None, modified));
+        assert_eq!(response_before.status(), StatusCode::OK);
+        let without_date = conditional(&Method::GET, &since, response(None, None));
+        assert_eq!(without_date.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,339 @@
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::compression::Encoding;
+
+/// Runtime configuration, read from environment variables on first use. Embedders start
+/// from `Config::default()` or `Config::from_env()` and change its fields
+#[derive(Serialize)]
+#[non_exhaustive]
+pub struct Config {
+    /// Upstream response headers whose values are hidden in the `headers` output
+    pub redacted_headers: Vec<String>,
+    /// Number of upstream requests run at the same time for one batch
+    pub batch_concurrency: usize,
+    /// Maximum number of requests accepted in one batch
+    pub batch_max_requests: usize,
+    /// The URL where clients reach this service, used for absolute links when rewriting HTML
+    pub public_url: Option<String>,
+    /// Largest page, in bytes, that is parsed for selections
+    pub select_max_document_size: usize,
+    /// Maximum number of elements returned by a selection
+    pub select_max_matches: usize,
+    /// Encodings used to compress responses, in order of preference. Empty disables compression
+    pub compression_encodings: Vec<Encoding>,
+    /// Smallest response, in bytes, that is compressed (when the size is known up front)
+    pub compression_min_size: u64,
+    /// Prefixes of the content types that are compressed
+    pub compression_content_types: Vec<String>,
+    /// Ask upstream for compressed responses and decompress them here
+    pub upstream_decompression: bool,
+    /// Relay compressed upstream responses unchanged through /raw, when the client accepts
+    /// the encoding and the contents are not changed by the service
+    pub raw_compression_passthrough: bool,
+    /// Concurrent identical GET and HEAD requests share one upstream fetch
+    pub coalesce_requests: bool,
+    /// Space, in bytes, for upstream pages that are revalidated instead of fetched again.
+    /// Zero disables the cache
+    pub cache_max_size: u64,
+    /// Directory the cache is kept in, so that it survives restarts. In memory when not set.
+    /// Only one process may use a directory at a time
+    pub cache_dir: Option<PathBuf>,
+    /// Redis server the cache is kept in, shared by all replicas. Hidden as it may hold a
+    /// password
+    #[serde(skip)]
+    pub cache_redis_url: Option<String>,
+    /// How long pages are kept in the cache, until evicted when not set (a day with Redis)
+    pub cache_ttl: Option<Duration>,
+    /// Requests per minute accepted from one client IP, zero for no limit
+    pub rate_limit_per_minute: u32,
+    /// Fail fast for upstream hosts that keep failing
+    pub circuit_breaker: bool,
+    /// Share of failed requests to a host, within the window, that opens its circuit
+    pub circuit_failure_rate: f64,
+    /// Fewer requests than this within the window never open the circuit
+    pub circuit_min_requests: u32,
+    /// How long requests to a host are counted, and how long an idle circuit is kept
+    pub circuit_window: Duration,
+    /// How long an open circuit fails fast before a probe request is let through
+    pub circuit_open_duration: Duration,
+    /// Maximum number of retries for idempotent requests
+    pub retry_max: u32,
+    /// Backoff before the first retry, doubled for each retry
+    pub retry_base_delay: Duration,
+    /// Longest wait before a retry, longer `Retry-After` delays are not retried
+    pub retry_max_delay: Duration,
+    /// Token required by the admin API, which only starts when it is set
+    #[serde(skip)]
+    pub admin_token: Option<String>,
+    /// Address the admin API listens on, only reachable from this host by default
+    pub admin_address: SocketAddr,
+    /// File the JSON Lines access log is written to, no access log when not set
+    pub access_log: Option<PathBuf>,
+    /// The access log is rotated before it grows larger than this, in bytes
+    pub access_log_max_size: u64,
+    /// The access log is rotated when it has been written to for this long
+    pub access_log_max_age: Duration,
+    /// Number of rotated access logs that are kept
+    pub access_log_retention: usize,
+    /// A proxied WebSocket is closed when no message was sent either way for this long
+    pub websocket_idle_timeout: Duration,
+    /// Largest WebSocket message, in bytes, accepted from the client or upstream
+    pub websocket_max_message_size: usize,
+    /// A heartbeat comment is sent on event streams that were quiet for this long, zero for none
+    pub sse_heartbeat: Duration,
+    /// Listen on the HTTP and HTTPS ports
+    pub tcp: bool,
+    /// Also listen on this Unix domain socket
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions of the Unix domain socket
+    pub unix_socket_mode: u32,
+    /// How long the service keeps accepting connections after a shutdown signal, while no
+    /// longer ready, so that load balancers stop sending requests first
+    pub shutdown_delay: Duration,
+    /// How long requests in flight get to finish after the service stops accepting connections
+    pub shutdown_timeout: Duration,
+    /// Secret for signed links. When set, every proxy request must be signed
+    #[serde(skip)]
+    pub signing_secret: Option<String>,
+}
+
+impl Default for Config {
+    /// The configuration when no environment variable is set
+    fn default() -> Self {
+        Config {
+            redacted_headers: ["set-cookie", "www-authenticate", "proxy-authenticate"]
+                .map(String::from)
+                .to_vec(),
+            batch_concurrency: 8,
+            batch_max_requests: 100,
+            public_url: None,
+            select_max_document_size: 5 * 1024 * 1024,
+            select_max_matches: 100,
+            compression_encodings: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip],
+            compression_min_size: 1024,
+            compression_content_types: [
+                "text/",
+                "application/json",
+                "application/x-ndjson",
+                "application/javascript",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+            upstream_decompression: true,
+            raw_compression_passthrough: false,
+            coalesce_requests: true,
+            cache_max_size: 64 * 1024 * 1024,
+            cache_dir: None,
+            cache_redis_url: None,
+            cache_ttl: None,
+            rate_limit_per_minute: 0,
+            circuit_breaker: true,
+            circuit_failure_rate: 0.5,
+            circuit_min_requests: 10,
+            circuit_window: Duration::from_secs(60),
+            circuit_open_duration: Duration::from_secs(30),
+            retry_max: 2,
+            retry_base_delay: Duration::from_millis(100),
+            retry_max_delay: Duration::from_secs(5),
+            admin_token: None,
+            admin_address: SocketAddr::from(([127, 0, 0, 1], 38726)),
+            access_log: None,
+            access_log_max_size: 100 * 1024 * 1024,
+            access_log_max_age: Duration::from_secs(24 * 60 * 60),
+            access_log_retention: 7,
+            websocket_idle_timeout: Duration::from_secs(300),
+            websocket_max_message_size: 1024 * 1024,
+            sse_heartbeat: Duration::from_secs(15),
+            tcp: true,
+            unix_socket: None,
+            unix_socket_mode: 0o660,
+            shutdown_delay: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(25),
+            signing_secret: None,
+        }
+    }
+}
+
+impl Config {
+    /// The default configuration, with the values set in the environment
+    pub fn from_env() -> Self {
+        let defaults = Config::default();
+        Config {
+            redacted_headers: env_list("ALL_ORIGINS_REDACTED_HEADERS")
+                .unwrap_or(defaults.redacted_headers),
+            batch_concurrency: env_positive("ALL_ORIGINS_BATCH_CONCURRENCY")
+                .unwrap_or(defaults.batch_concurrency),
+            batch_max_requests: env_positive("ALL_ORIGINS_BATCH_MAX_REQUESTS")
+                .unwrap_or(defaults.batch_max_requests),
+            public_url: env::var("ALL_ORIGINS_PUBLIC_URL").ok(),
+            select_max_document_size: env_parse("ALL_ORIGINS_SELECT_MAX_DOCUMENT_SIZE")
+                .unwrap_or(defaults.select_max_document_size),
+            select_max_matches: env_parse("ALL_ORIGINS_SELECT_MAX_MATCHES")
+                .unwrap_or(defaults.select_max_matches),
+            compression_encodings: env_list("ALL_ORIGINS_COMPRESSION")
+                .map(|encodings| {
+                    encodings
+                        .iter()
+                        .filter_map(|encoding| match Encoding::from_str(encoding) {
+                            Ok(encoding) => Some(encoding),
+                            Err(message) => {
+                                println!("Ignoring compression: {message}");
+                                None
+                            }
+                        })
+                        .collect()
+                })
+                .unwrap_or(defaults.compression_encodings),
+            compression_min_size: env_parse("ALL_ORIGINS_COMPRESSION_MIN_SIZE")
+                .unwrap_or(defaults.compression_min_size),
+            compression_content_types: env_list("ALL_ORIGINS_COMPRESSION_CONTENT_TYPES")
+                .unwrap_or(defaults.compression_content_types),
+            upstream_decompression: env_parse("ALL_ORIGINS_UPSTREAM_DECOMPRESSION")
+                .unwrap_or(defaults.upstream_decompression),
+            raw_compression_passthrough: env_parse("ALL_ORIGINS_RAW_COMPRESSION_PASSTHROUGH")
+                .unwrap_or(defaults.raw_compression_passthrough),
+            coalesce_requests: env_parse("ALL_ORIGINS_COALESCE_REQUESTS")
+                .unwrap_or(defaults.coalesce_requests),
+            cache_max_size: env_parse("ALL_ORIGINS_CACHE_MAX_SIZE")
+                .unwrap_or(defaults.cache_max_size),
+            cache_dir: env::var_os("ALL_ORIGINS_CACHE_DIR").map(PathBuf::from),
+            cache_redis_url: env::var("ALL_ORIGINS_CACHE_REDIS_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            cache_ttl: env_seconds("ALL_ORIGINS_CACHE_TTL_SECONDS"),
+            circuit_breaker: env_parse("ALL_ORIGINS_CIRCUIT_BREAKER")
+                .unwrap_or(defaults.circuit_breaker),
+            circuit_failure_rate: env_parse("ALL_ORIGINS_CIRCUIT_FAILURE_RATE")
+                .unwrap_or(defaults.circuit_failure_rate),
+            circuit_min_requests: env_parse("ALL_ORIGINS_CIRCUIT_MIN_REQUESTS")
+                .unwrap_or(defaults.circuit_min_requests),
+            rate_limit_per_minute: env_parse("ALL_ORIGINS_RATE_LIMIT_PER_MINUTE")
+                .unwrap_or(defaults.rate_limit_per_minute),
+            circuit_window: env_seconds("ALL_ORIGINS_CIRCUIT_WINDOW_SECONDS")
+                .unwrap_or(defaults.circuit_window),
+            circuit_open_duration: env_seconds("ALL_ORIGINS_CIRCUIT_OPEN_SECONDS")
+                .unwrap_or(defaults.circuit_open_duration),
+            retry_max: env_parse("ALL_ORIGINS_RETRY_MAX").unwrap_or(defaults.retry_max),
+            retry_base_delay: env_parse("ALL_ORIGINS_RETRY_BASE_DELAY_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.retry_base_delay),
+            retry_max_delay: env_parse("ALL_ORIGINS_RETRY_MAX_DELAY_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.retry_max_delay),
+            admin_token: env::var("ALL_ORIGINS_ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            admin_address: env_parse("ALL_ORIGINS_ADMIN_ADDRESS").unwrap_or(defaults.admin_address),
+            access_log: env::var_os("ALL_ORIGINS_ACCESS_LOG").map(PathBuf::from),
+            access_log_max_size: env_parse("ALL_ORIGINS_ACCESS_LOG_MAX_SIZE")
+                .unwrap_or(defaults.access_log_max_size),
+            access_log_max_age: env_seconds("ALL_ORIGINS_ACCESS_LOG_ROTATE_SECONDS")
+                .unwrap_or(defaults.access_log_max_age),
+            access_log_retention: env_parse("ALL_ORIGINS_ACCESS_LOG_RETENTION")
+                .unwrap_or(defaults.access_log_retention),
+            websocket_idle_timeout: env_seconds("ALL_ORIGINS_WEBSOCKET_IDLE_SECONDS")
+                .unwrap_or(defaults.websocket_idle_timeout),
+            websocket_max_message_size: env_parse("ALL_ORIGINS_WEBSOCKET_MAX_MESSAGE_SIZE")
+                .unwrap_or(defaults.websocket_max_message_size),
+            sse_heartbeat: env_seconds("ALL_ORIGINS_SSE_HEARTBEAT_SECONDS")
+                .unwrap_or(defaults.sse_heartbeat),
+            tcp: env_parse("ALL_ORIGINS_TCP").unwrap_or(defaults.tcp),
+            unix_socket: env::var_os("ALL_ORIGINS_UNIX_SOCKET").map(PathBuf::from),
+            unix_socket_mode: env_mode("ALL_ORIGINS_UNIX_SOCKET_MODE")
+                .unwrap_or(defaults.unix_socket_mode),
+            shutdown_delay: env_seconds("ALL_ORIGINS_SHUTDOWN_DELAY_SECONDS")
+                .unwrap_or(defaults.shutdown_delay),
+            shutdown_timeout: env_seconds("ALL_ORIGINS_SHUTDOWN_TIMEOUT_SECONDS")
+                .unwrap_or(defaults.shutdown_timeout),
+            signing_secret: env::var("ALL_ORIGINS_SIGNING_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+        }
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The configuration of the running service
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::from_env)
+}
+
+/// Sets the configuration instead of reading it from the environment, before it is first used
+pub(crate) fn set_config(config: Config) -> Result<(), String> {
+    CONFIG
+        .set(config)
+        .map_err(|_| "The configuration is already in use".to_string())
+}
+
+/// Comma separated list, lowercased and trimmed. An empty value gives an empty list
+fn env_list(name: &str) -> Option<Vec<String>> {
+    env::var(name).ok().map(|value| {
+        value
+            .split(',')
+            .map(|s| s.trim().to_ascii_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect()
+    })
+}
+
+/// Parsed value, invalid values are reported and ignored
+fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.trim().parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            println!("Ignoring invalid value for {name}: {value}");
+            None
+        }
+    }
+}
+
+/// Parsed value that must not be zero, zero is reported and ignored
+fn env_positive(name: &str) -> Option<usize> {
+    let value = env_parse(name)?;
+    if value == 0 {
+        println!("Ignoring invalid value for {name}: 0");
+        return None;
+    }
+    Some(value)
+}
+
+/// Octal file permissions, like 660
+fn env_mode(name: &str) -> Option<u32> {
+    let value = env::var(name).ok()?;
+    match u32::from_str_radix(value.trim(), 8) {
+        Ok(mode) if mode <= 0o777 => Some(mode),
+        _ => {
+            println!("Ignoring invalid value for {name}: {value}");
+            None
+        }
+    }
+}
+
+fn env_seconds(name: &str) -> Option<Duration> {
+    env_parse(name).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_should_not_be_positive() {
+        env::set_var("ALL_ORIGINS_TEST_ZERO", "0");
+        env::set_var("ALL_ORIGINS_TEST_FOUR", " 4");
+
+        assert_eq!(env_positive("ALL_ORIGINS_TEST_ZERO"), None);
+        assert_eq!(env_positive("ALL_ORIGINS_TEST_FOUR"), Some(4));
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use futures_util::{stream, Stream, StreamExt};
+use warp::hyper::body::Bytes;
+
+/// Comment line sent while the upstream is quiet, ignored by event stream clients
+const HEARTBEAT: &[u8] = b": heartbeat\n";
+
+pub fn is_event_stream(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|content_type| {
+        content_type
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with("text/event-stream")
+    })
+}
+
+/// Relays the chunks as they arrive, with a heartbeat comment when nothing arrived for
+/// `heartbeat`. Heartbeats are only sent between lines, so events are never split.
+/// A zero `heartbeat` sends none
+pub fn with_heartbeat<S, E>(
+    chunks: S,
+    heartbeat: Duration,
+) -> impl Stream<Item = Result<Bytes, E>> + Send
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + Unpin,
+{
+    stream::unfold(Some((chunks, true)), move |state| async move {
+        let (mut chunks, mut line_start) = state?;
+        loop {
+            let next = if heartbeat.is_zero() {
+                Ok(chunks.next().await)
+            } else {
+                tokio::time::timeout(heartbeat, chunks.next()).await
+            };
+            match next {
+                Ok(Some(Ok(chunk))) => {
+                    if !chunk.is_empty() {
+                        line_start = chunk.ends_with(b"\n");
+                    }
+                    return Some((Ok(chunk), Some((chunks, line_start))));
+                }
+                Ok(Some(Err(err))) => return Some((Err(err), None)),
+                Ok(None) => return None,
+                Err(_) if line_start => {
+                    let heartbeat = Bytes::from_static(HEARTBEAT);
+                    return Some((Ok(heartbeat), Some((chunks, line_start))));
+                }
+                Err(_) => continue,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    fn delayed(chunks: Vec<(u64, &'static str)>) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        stream::iter(chunks).then(|(delay, chunk)| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            Ok(Bytes::from_static(chunk.as_bytes()))
+        })
+    }
+
+    async fn relayed(chunks: Vec<(u64, &'static str)>) -> String {
+        let relayed: Vec<Bytes> =
+            with_heartbeat(Box::pin(delayed(chunks)), Duration::from_millis(40))
+                .map(Result::unwrap)
+                .collect()
+                .await;
+        relayed
+            .iter()
+            .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn heartbeat_should_be_sent_between_events() {
+        let relayed = relayed(vec![(0, "data: a\n\n"), (100, "data: b\n\n")]).await;
+
+        assert!(relayed.starts_with("data: a\n\n: heartbeat\n"));
+        assert!(relayed.ends_with("data: b\n\n"));
+    }
+
+    #[tokio::test]
+    async fn heartbeat_should_not_split_lines() {
+        let relayed = relayed(vec![(0, "data: a"), (100, "bc\n\n")]).await;
+
+        assert_eq!(relayed, "data: abc\n\n");
+    }
+
+    #[test]
+    fn event_stream_should_be_recognised() {
+        assert!(is_event_stream(Some("text/event-stream")));
+        assert!(is_event_stream(Some("Text/Event-Stream; charset=utf-8")));
+        assert!(!is_event_stream(Some("text/html")));
+        assert!(!is_event_stream(None));
+    }
+}
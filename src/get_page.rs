@@ -1,60 +1,354 @@
-use reqwest::{header, Method};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-use crate::page_types::PageContent;
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{header, Client, ClientBuilder, Method, Request, Response};
+
+use crate::cache::{cache, is_revalidatable, CachedPage, HIT, MISS};
+use crate::circuit_breaker::{circuit_breaker, circuit_key, CIRCUIT_OPEN};
+use crate::coalesce::coalesce;
+use crate::config::config;
+use crate::page_types::{header_values, PageContent};
+use crate::retry::{backoff, is_idempotent, is_transient, retry_after};
 use crate::VERSION;
 
+/// Changes the client of upstream requests, like its proxy or timeouts
+pub(crate) type ClientHook = Box<dyn Fn(ClientBuilder) -> ClientBuilder + Send + Sync>;
+
+static CLIENT_HOOK: OnceLock<ClientHook> = OnceLock::new();
+
+/// Sets how the client of upstream requests is changed, once for the process
+pub(crate) fn set_client_hook(hook: ClientHook) -> Result<(), String> {
+    CLIENT_HOOK
+        .set(hook)
+        .map_err(|_| "The client is already configured".to_string())
+}
+
 /// Get external web page given a URL
+#[derive(Clone)]
 pub struct GetPage {
     url: String,
+    include_headers: bool,
+    request_headers: HeaderMap,
+    passthrough_encoding: Option<HeaderValue>,
+    max_size: Option<usize>,
 }
 
 impl GetPage {
-    pub(crate) fn new(url: String) -> Self {
-        Self { url }
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            include_headers: false,
+            request_headers: HeaderMap::new(),
+            passthrough_encoding: None,
+            max_size: None,
+        }
+    }
+
+    /// Extra headers to send upstream
+    pub fn with_request_headers(mut self, request_headers: HeaderMap) -> Self {
+        self.request_headers = request_headers;
+        self
+    }
+
+    /// Include all upstream response headers in the result
+    pub fn with_headers(mut self, include_headers: bool) -> Self {
+        self.include_headers = include_headers;
+        self
     }
 
+    /// Ask upstream for the encodings the client accepts and keep the body compressed,
+    /// instead of decompressing it here
+    pub(crate) fn with_compression_passthrough(mut self, accept_encoding: &str) -> Self {
+        self.passthrough_encoding = HeaderValue::from_str(accept_encoding).ok();
+        self
+    }
+
+    /// Fail with `too_large` instead of downloading a body larger than `max_size` bytes
+    pub(crate) fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Status, content type and length of the page, with a HEAD request
     pub async fn get_page_info(&self) -> PageContent {
-        let client = reqwest::Client::builder()
-            .danger_accept_invalid_certs(true)
-            .build()
-            .unwrap();
-        let response = client
-            .request(Method::HEAD, self.url.clone())
-            .header(
-                header::USER_AGENT,
-                format!("Mozilla/5.0 (compatible; all_origins_rust/{VERSION}"),
-            )
-            .send()
-            .await;
-        match response {
-            Ok(response) => PageContent::info(response),
-            Err(err) => PageContent::error(err, self.url.to_string()),
+        match self.coalesce_key("INFO") {
+            Some(key) => {
+                let page = self.clone();
+                coalesce(key, async move { page.fetch_page_info().await }).await
+            }
+            None => self.fetch_page_info().await,
         }
     }
 
+    /// The page with its contents, decoded to UTF-8
     pub async fn get_page(&self, method: Method) -> PageContent {
-        let client = reqwest::Client::builder()
+        let safe = method == Method::GET || method == Method::HEAD;
+        match self.coalesce_key(method.as_str()).filter(|_| safe) {
+            Some(key) => {
+                let page = self.clone();
+                coalesce(key, async move { page.fetch_page(method).await }).await
+            }
+            None => self.fetch_page(method).await,
+        }
+    }
+
+    /// Identical requests have the same key, None if requests should not be coalesced
+    fn coalesce_key(&self, kind: &str) -> Option<String> {
+        let max_size = self
+            .max_size
+            .map(|max_size| format!(" max-size:{max_size}"));
+        config()
+            .coalesce_requests
+            .then(|| self.request_key(kind) + &max_size.unwrap_or_default())
+    }
+
+    /// The key of a GET in the cache and the cached page, None if it is not cached.
+    /// Ranges and requests that are conditional themselves are not, upstream answers those
+    pub(crate) async fn cache_lookup(
+        &self,
+        method: &Method,
+    ) -> Option<(String, Option<CachedPage>)> {
+        let cache = cache()?;
+        let uncached = [
+            header::IF_NONE_MATCH,
+            header::IF_MODIFIED_SINCE,
+            header::RANGE,
+        ];
+        let conditional = uncached
+            .iter()
+            .any(|name| self.request_headers.contains_key(name));
+        if method != Method::GET || self.passthrough_encoding.is_some() || conditional {
+            return None;
+        }
+        let key = self.request_key(method.as_str());
+        let cached = cache.get(&key).await;
+        Some((key, cached))
+    }
+
+    /// Identical requests have the same key
+    fn request_key(&self, kind: &str) -> String {
+        let mut key = format!("{kind} {} {}", self.url, self.include_headers);
+        if let Some(ref accept_encoding) = self.passthrough_encoding {
+            key.push_str(&format!(" accept-encoding:{accept_encoding:?}"));
+        }
+        let mut headers: Vec<String> = self
+            .request_headers
+            .iter()
+            .map(|(name, value)| format!(" {name}:{value:?}"))
+            .collect();
+        headers.sort();
+        key.extend(headers);
+        key
+    }
+
+    /// The info of a page in the cache comes from the cache, when a conditional HEAD
+    /// request tells that it was not modified
+    async fn fetch_page_info(&self) -> PageContent {
+        let cached = self
+            .cache_lookup(&Method::GET)
+            .await
+            .and_then(|(_, cached)| cached);
+        let page = self.revalidating(cached.as_ref());
+        match page.send(Method::HEAD).await {
+            Ok((response, retries)) => {
+                if let Some(cached) = cached.filter(|_| response.status() == 304) {
+                    cache().unwrap().record(HIT);
+                    return PageContent {
+                        content_length: Some(cached.body.len() as u64),
+                        retries,
+                        cache: Some(HIT),
+                        ..cached.content
+                    };
+                }
+                let headers = self.response_headers(&response);
+                let mut content = PageContent::info(response);
+                content.headers = headers;
+                content.retries = retries;
+                content
+            }
+            Err(content) => content,
+        }
+    }
+
+    /// A page in the cache is revalidated with a conditional request, and kept when upstream
+    /// answers that it was not modified
+    async fn fetch_page(&self, method: Method) -> PageContent {
+        let (cache_key, cached) = match self.cache_lookup(&method).await {
+            Some((key, cached)) => (Some(key), cached),
+            None => (None, None),
+        };
+        let page = self.revalidating(cached.as_ref());
+        match page.send(method).await {
+            Ok((response, retries)) => {
+                if let Some(cached) = cached.filter(|_| response.status() == 304) {
+                    cache().unwrap().record(HIT);
+                    let mut content = PageContent {
+                        retries,
+                        ..cached.page_content()
+                    };
+                    if let Some(max_size) = self.max_size.filter(|&max| cached.body.len() > max) {
+                        content.contents = None;
+                        content.fail("too_large", format!("Page is larger than {max_size} bytes"));
+                    }
+                    return content;
+                }
+                let headers = self.response_headers(&response);
+                let (mut content, body) = match self.max_size {
+                    Some(max_size) => PageContent::data_and_body_up_to(response, max_size).await,
+                    None => PageContent::data_and_body(response).await,
+                };
+                content.headers = headers;
+                content.retries = retries;
+                if let Some(key) = cache_key {
+                    let cache = cache().unwrap();
+                    cache.record(MISS);
+                    content.cache = Some(MISS);
+                    if is_revalidatable(&content) {
+                        cache.insert(&key, &self.url, &content, body).await;
+                    }
+                }
+                content
+            }
+            Err(content) => content,
+        }
+    }
+
+    /// The request with the validators of the cached page, if there is one
+    pub(crate) fn revalidating(&self, cached: Option<&CachedPage>) -> Self {
+        let mut page = self.clone();
+        let Some(cached) = cached.map(|cached| &cached.content) else {
+            return page;
+        };
+        let validators = [
+            (header::IF_NONE_MATCH, &cached.upstream_etag),
+            (header::IF_MODIFIED_SINCE, &cached.last_modified),
+        ];
+        for (name, value) in validators {
+            if let Some(value) = value.as_ref().and_then(|v| HeaderValue::from_str(v).ok()) {
+                page.request_headers.insert(name, value);
+            }
+        }
+        page
+    }
+
+    /// Send the request, the body of the response is not read yet. Idempotent requests are
+    /// retried on connection errors and transient statuses, the number of retries is returned
+    pub(crate) async fn send(&self, method: Method) -> Result<(Response, u32), PageContent> {
+        // A range of a compressed body can not be decompressed on its own
+        let ranged = self.request_headers.contains_key(header::RANGE);
+        let decompress =
+            self.passthrough_encoding.is_none() && !ranged && config().upstream_decompression;
+        let mut client = reqwest::Client::builder()
             .danger_accept_invalid_certs(true)
+            .gzip(decompress)
+            .brotli(decompress)
+            .zstd(decompress)
+            .deflate(decompress);
+        if let Some(hook) = CLIENT_HOOK.get() {
+            client = hook(client);
+        }
+        let client = client
             .build()
-            .unwrap();
-        let response = client
+            .map_err(|err| PageContent::error(err, self.url.to_string()))?;
+        let max_retries = if is_idempotent(&method) {
+            config().retry_max
+        } else {
+            0
+        };
+        let mut request = client
             .request(method, self.url.clone())
-            .header(
-                header::USER_AGENT,
-                format!("Mozilla/5.0 (compatible; all_origins_rust/{VERSION}"),
-            )
-            .send()
-            .await;
-        match response {
-            Ok(response) => PageContent::data(response).await,
-            Err(err) => PageContent::error(err, self.url.to_string()),
+            .header(header::USER_AGENT, user_agent())
+            .headers(self.request_headers.clone());
+        if let Some(ref accept_encoding) = self.passthrough_encoding {
+            request = request.header(header::ACCEPT_ENCODING, accept_encoding);
+        }
+        let request = request
+            .build()
+            .map_err(|err| PageContent::error(err, self.url.to_string()))?;
+
+        // The circuit breaker counts the request once, whatever its retries
+        let host = circuit_key(self.url.as_str());
+        if let Some(ref host) = host {
+            if !circuit_breaker().allow(host) {
+                return Err(
+                    PageContent::invalid(CIRCUIT_OPEN.to_string(), self.url.to_string())
+                        .with_error_code(CIRCUIT_OPEN),
+                );
+            }
         }
+        let result = self.send_with_retries(&client, request, max_retries).await;
+        if let Some(ref host) = host {
+            let success = matches!(&result, Ok((r, _)) if !r.status().is_server_error());
+            circuit_breaker().record(host, success);
+        }
+        result
     }
+
+    /// Sends the request, transient failures are retried with backoff
+    async fn send_with_retries(
+        &self,
+        client: &Client,
+        request: Request,
+        max_retries: u32,
+    ) -> Result<(Response, u32), PageContent> {
+        let mut retries = 0;
+        loop {
+            let response = client
+                .execute(request.try_clone().unwrap())
+                .await
+                .map_err(|err| PageContent::error(err, self.url.to_string()));
+            let delay = match response {
+                Ok(ref response) if is_transient(response.status()) => {
+                    Some(retry_after(response.headers()).unwrap_or(Duration::ZERO))
+                }
+                Ok(_) => return response.map(|response| (response, retries)),
+                Err(_) => Some(Duration::ZERO),
+            };
+            let delay = delay.map(|delay| {
+                delay.max(backoff(
+                    retries,
+                    config().retry_base_delay,
+                    config().retry_max_delay,
+                ))
+            });
+            match delay {
+                Some(delay) if retries < max_retries && delay <= config().retry_max_delay => {
+                    retries += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                _ => {
+                    return response
+                        .map(|response| (response, retries))
+                        .map_err(|mut content| {
+                            content.retries = retries;
+                            content
+                        })
+                }
+            }
+        }
+    }
+
+    fn response_headers(&self, response: &Response) -> Option<BTreeMap<String, Vec<String>>> {
+        self.include_headers
+            .then(|| header_values(response.headers(), &config().redacted_headers))
+    }
+}
+
+/// The User-Agent of upstream requests, unless the request sets its own
+pub fn user_agent() -> String {
+    format!("Mozilla/5.0 (compatible; all_origins_rust/{VERSION}")
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
-    use wiremock::matchers::{method, path};
+    use async_compression::tokio::write::GzipEncoder;
+    use futures_util::future::join_all;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use wiremock::matchers::{header_exists, method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
     use super::*;
@@ -157,6 +451,214 @@ mod tests {
         assert_eq!(page_content.contents.is_none(), true);
     }
 
+    #[tokio::test]
+    async fn headers_should_only_be_included_when_asked_for() {
+        let server = setup().await;
+        let url = format!("{}/example", server.uri());
+
+        let page_content = GetPage::new(url.clone()).get_page(Method::GET).await;
+        assert!(page_content.headers.is_none());
+
+        let page_content = GetPage::new(url)
+            .with_headers(true)
+            .get_page(Method::GET)
+            .await;
+        let headers = page_content.headers.unwrap();
+        assert_eq!(headers["content-type"], vec!["text/plain"]);
+        assert_eq!(headers["x-multi"], vec!["one", "two"]);
+        assert_eq!(headers["set-cookie"], vec!["[redacted]"]);
+    }
+
+    #[tokio::test]
+    async fn get_page_info_should_include_headers() {
+        let server = setup().await;
+        let page_content = GetPage::new(format!("{}/example", server.uri()))
+            .with_headers(true)
+            .get_page_info()
+            .await;
+
+        let headers = page_content.headers.unwrap();
+        assert_eq!(headers["content-length"], vec!["11"]);
+    }
+
+    #[tokio::test]
+    async fn compressed_response_should_be_decompressed() {
+        let server = setup_compressed().await;
+        let page_content = GetPage::new(format!("{}/compressed", server.uri()))
+            .get_page(Method::GET)
+            .await;
+
+        assert_eq!(page_content.contents.unwrap(), "Hello, Compressed");
+    }
+
+    #[tokio::test]
+    async fn page_larger_than_max_size_should_fail() {
+        let server = setup().await;
+        let page_content = GetPage::new(format!("{}/example", server.uri()))
+            .with_max_size(4)
+            .get_page(Method::GET)
+            .await;
+
+        assert_eq!(page_content.error_code, Some("too_large"));
+        assert!(page_content.contents.is_none());
+    }
+
+    #[tokio::test]
+    async fn decompressed_page_larger_than_max_size_should_fail() {
+        // The decompressed body has no length, it is counted while it is read
+        let server = setup_compressed().await;
+        let page = GetPage::new(format!("{}/compressed", server.uri()));
+
+        let page_content = page.clone().with_max_size(10).get_page(Method::GET).await;
+        assert_eq!(page_content.error_code, Some("too_large"));
+
+        let page_content = page.with_max_size(17).get_page(Method::GET).await;
+        assert_eq!(page_content.contents.unwrap(), "Hello, Compressed");
+    }
+
+    #[tokio::test]
+    async fn compressed_response_can_be_passed_through() {
+        let server = setup_compressed().await;
+        let response = GetPage::new(format!("{}/compressed", server.uri()))
+            .with_compression_passthrough("gzip")
+            .send(Method::GET)
+            .await
+            .ok()
+            .unwrap()
+            .0;
+
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            response.bytes().await.unwrap(),
+            gzip("Hello, Compressed").await
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_identical_requests_should_be_coalesced() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/slow"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("Hello, Slow")
+                    .set_delay(Duration::from_millis(100)),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let url = format!("{}/slow", server.uri());
+        let pages = (0..10).map(|_| GetPage::new(url.clone()));
+        let contents =
+            join_all(pages.map(|page| async move { page.get_page(Method::GET).await })).await;
+
+        assert!(contents
+            .into_iter()
+            .all(|content| content.contents.as_deref() == Some("Hello, Slow")));
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn transient_failure_should_be_retried() {
+        let server = setup_flaky("GET").await;
+        let page_content = GetPage::new(format!("{}/flaky", server.uri()))
+            .get_page(Method::GET)
+            .await;
+
+        assert_eq!(page_content.http_code.unwrap(), 200);
+        assert_eq!(page_content.contents.unwrap(), "Hello, Again");
+        assert_eq!(page_content.retries, 1);
+    }
+
+    #[tokio::test]
+    async fn retried_request_should_count_once_for_circuit() {
+        let server = setup_flaky("GET").await;
+        let url = format!("{}/flaky", server.uri());
+        let host = circuit_key(&url).unwrap();
+        let requests = || {
+            circuit_breaker()
+                .status()
+                .get(&host)
+                .and_then(|status| status.requests)
+                .unwrap_or(0)
+        };
+        let before = requests();
+
+        let page_content = GetPage::new(url).get_page(Method::GET).await;
+
+        assert_eq!(page_content.retries, 1);
+        assert_eq!(requests(), before + 1);
+    }
+
+    #[tokio::test]
+    async fn post_should_not_be_retried() {
+        let server = setup_flaky("POST").await;
+        let page_content = GetPage::new(format!("{}/flaky", server.uri()))
+            .get_page(Method::POST)
+            .await;
+
+        assert_eq!(page_content.http_code.unwrap(), 503);
+        assert_eq!(page_content.retries, 0);
+        assert_eq!(page_content.error_code, Some("upstream_status"));
+    }
+
+    #[tokio::test]
+    async fn refused_connection_should_have_error_code() {
+        let page_content = GetPage::new("http://127.0.0.1:1/refused".to_string())
+            .get_page(Method::POST)
+            .await;
+
+        assert!(page_content.error.is_some());
+        assert_eq!(page_content.error_code, Some("connect"));
+    }
+
+    /// Fails once with 503 and then succeeds
+    async fn setup_flaky(http_method: &str) -> MockServer {
+        let server = MockServer::start().await;
+
+        Mock::given(method(http_method))
+            .and(path("/flaky"))
+            .respond_with(ResponseTemplate::new(503).append_header("retry-after", "0"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+
+        Mock::given(method(http_method))
+            .and(path("/flaky"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello, Again"))
+            .mount(&server)
+            .await;
+
+        server
+    }
+
+    async fn setup_compressed() -> MockServer {
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/compressed"))
+            .and(header_exists(header::ACCEPT_ENCODING.as_str()))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .append_header(header::CONTENT_TYPE.as_str(), "text/plain")
+                    .append_header(header::CONTENT_ENCODING.as_str(), "gzip")
+                    .set_body_bytes(gzip("Hello, Compressed").await),
+            )
+            .mount(&server)
+            .await;
+
+        server
+    }
+
+    async fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder.write_all(text.as_bytes()).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        encoder.into_inner()
+    }
+
     async fn setup() -> MockServer {
         let server = MockServer::start().await;
 
@@ -166,6 +668,9 @@ mod tests {
             .respond_with(
                 ResponseTemplate::new(200)
                     .append_header(header::CONTENT_TYPE.as_str(), "text/plain")
+                    .append_header("x-multi", "one")
+                    .append_header("x-multi", "two")
+                    .append_header(header::SET_COOKIE.as_str(), "secret=1")
                     .set_body_string("Hello, Get"),
             )
             .mount(&server)
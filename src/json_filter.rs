@@ -0,0 +1,40 @@
+use jmespath::Variable;
+
+/// Applies a JMESPath `expression` to a JSON document, the result is compact JSON
+pub fn filter_json(json: &str, expression: &str) -> Result<String, String> {
+    let expression =
+        jmespath::compile(expression).map_err(|err| format!("Invalid filter expression: {err}"))?;
+    let data = Variable::from_json(json).map_err(|err| format!("Invalid JSON: {err}"))?;
+    let result = expression
+        .search(data)
+        .map_err(|err| format!("Filter failed: {err}"))?;
+    serde_json::to_string(&*result).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{"items": [{"id": 1, "name": "a"}, {"id": 2, "name": "b"}], "total": 2}"#;
+
+    #[test]
+    fn expression_should_select_subset() {
+        assert_eq!(filter_json(JSON, "total").unwrap(), "2");
+        assert_eq!(filter_json(JSON, "items[].name").unwrap(), r#"["a","b"]"#);
+        assert_eq!(
+            filter_json(JSON, "items[?id > `1`] | [0]").unwrap(),
+            r#"{"id":2,"name":"b"}"#
+        );
+        assert_eq!(filter_json(JSON, "missing").unwrap(), "null");
+    }
+
+    #[test]
+    fn errors_should_be_reported() {
+        assert!(filter_json(JSON, "items[")
+            .unwrap_err()
+            .starts_with("Invalid filter expression"));
+        assert!(filter_json("<html>", "total")
+            .unwrap_err()
+            .starts_with("Invalid JSON"));
+    }
+}
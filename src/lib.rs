@@ -0,0 +1,62 @@
+//! A CORS proxy: pages of other origins as JSON or as they are, for browsers.
+//! The binary runs the service, [`Builder`] mounts it in another warp application
+use futures_util::FutureExt;
+
+mod access_log;
+mod activity;
+mod admin;
+mod app_test;
+mod builder;
+mod cache;
+mod charset;
+mod circuit_breaker;
+mod coalesce;
+mod compression;
+mod conditional;
+mod config;
+mod event_stream;
+mod get_page;
+mod json_filter;
+mod openapi;
+mod page_types;
+mod preview;
+mod process_request;
+mod rate_limit;
+mod redis_cache;
+mod retry;
+mod rewrite;
+mod select;
+mod server;
+mod shutdown;
+mod signing;
+mod websocket;
+
+pub use builder::Builder;
+pub use compression::Encoding as CompressionEncoding;
+pub use config::Config;
+pub use get_page::GetPage;
+pub use page_types::PageContent;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Runs the `sign` command given in the arguments, or else the service until it is shut down
+pub async fn run(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some("sign") = args.first().map(String::as_str) {
+        let config = config::config();
+        let link = signing::sign_command(
+            config.signing_secret.as_deref(),
+            config.public_url.as_deref(),
+            &args[1..],
+        )?;
+        println!("{link}");
+        return Ok(());
+    }
+
+    println!("Starting all_origins_rust {VERSION}");
+    let mut servers = server::start().await?;
+    if let Some(admin_server) = admin::start() {
+        servers.push(admin_server.boxed());
+    }
+    shutdown::run(servers).await;
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use utoipa::OpenApi;
+use warp::{Filter, Rejection, Reply};
+
+use crate::page_types::{BatchItem, BatchRequest, PageContent};
+use crate::preview::Preview;
+use crate::select::Selection;
+use crate::server;
+
+/// The OpenAPI document, generated from the handlers and the types they use
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        description = "Web proxy that fetches the page of a URL, to avoid CORS problems",
+        license(name = "GPL-3.0")
+    ),
+    paths(
+        server::info_handler,
+        server::get_handler,
+        server::raw_handler,
+        server::batch_handler,
+        server::preview_handler,
+        server::select_handler,
+        server::ws_handler,
+        server::ready_handler,
+        openapi_handler,
+    ),
+    components(schemas(PageContent, BatchRequest, BatchItem, Preview, Selection))
+)]
+struct ApiDoc;
+
+/// The path for openapi.json
+pub fn openapi_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path("openapi.json")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(openapi_handler)
+}
+
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    operation_id = "openapi",
+    tag = "service",
+    responses(
+        (status = 200, description = "This document", content_type = "application/json", body = Object),
+    )
+)]
+fn openapi_handler() -> impl Reply {
+    warp::reply::json(&ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+
+    /// The routes of `all_filters`, update along with them
+    const ROUTES: [&str; 9] = [
+        "/batch",
+        "/get",
+        "/info",
+        "/openapi.json",
+        "/preview",
+        "/raw",
+        "/ready",
+        "/select",
+        "/ws",
+    ];
+
+    #[test]
+    fn every_route_should_be_documented() {
+        let mut documented: Vec<String> = ApiDoc::openapi().paths.paths.into_keys().collect();
+        documented.sort();
+
+        assert_eq!(documented, ROUTES);
+    }
+
+    #[test]
+    fn page_content_schema_should_match_serialized_fields() {
+        let document = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let properties = &document["components"]["schemas"]["PageContent"]["properties"];
+
+        let mut content = PageContent::invalid("error".to_string(), "url".to_string());
+        content.content_length = Some(1);
+        content.content_type = Some("text/plain".to_string());
+        content.http_code = Some(200);
+        content.contents = Some("contents".to_string());
+        content.detected_charset = Some("UTF-8".to_string());
+        content.headers = Some(BTreeMap::new());
+        content.retries = 1;
+        let Value::Object(fields) = serde_json::to_value(&content).unwrap() else {
+            panic!("PageContent is not an object");
+        };
+
+        let mut schema_fields: Vec<&String> = properties.as_object().unwrap().keys().collect();
+        let mut fields: Vec<&String> = fields.keys().collect();
+        schema_fields.sort();
+        fields.sort();
+        assert_eq!(schema_fields, fields);
+    }
+}
@@ -1,25 +1,68 @@
+use std::collections::BTreeMap;
+
+use reqwest::header::HeaderMap;
 use reqwest::{header, Error, Response};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use warp::hyper::body::Bytes;
+
+use crate::charset::decode;
+
+/// Replaces the value of redacted headers
+const REDACTED: &str = "[redacted]";
 
 /// Return data from service
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct PageContent {
+    /// Size of the contents, in bytes
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content_length: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content_type: Option<String>,
+    /// HTTP status code of the upstream response
     #[serde(skip_serializing_if = "Option::is_none")]
     pub http_code: Option<u16>,
+    /// Time to fetch the page, in milliseconds
     pub response_time: u32,
     pub url: String,
+    /// The page, decoded to UTF-8. Not included by info
     #[serde(skip_serializing_if = "Option::is_none")]
     pub contents: Option<String>,
+    /// The charset the page was decoded from, found in its byte order mark, content type or
+    /// `<meta>` tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detected_charset: Option<String>,
+    /// Why the page could not be fetched
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    /// All upstream response headers, only when requested with `headers=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<BTreeMap<String, Vec<String>>>,
+    /// Number of times the upstream request was retried
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+    /// The `ETag` of the upstream response
+    #[serde(skip)]
+    pub(crate) upstream_etag: Option<String>,
+    /// The `Last-Modified` of the upstream response
+    #[serde(skip)]
+    pub(crate) last_modified: Option<String>,
+    /// Whether the page came from the cache, for the access log
+    #[serde(skip)]
+    pub(crate) cache: Option<&'static str>,
+    /// What kind of error `error` is, for the access log
+    #[serde(skip)]
+    pub(crate) error_code: Option<&'static str>,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
 }
 
 impl PageContent {
-    pub fn info(resp: Response) -> Self {
+    /// The page as far as the status and headers of the response tell, without contents
+    pub(crate) fn from_headers(resp: &Response) -> Self {
+        let (upstream_etag, last_modified) = validators(resp.headers());
         PageContent {
             url: resp.url().to_string(),
             content_type: resp
@@ -27,59 +70,187 @@ impl PageContent {
                 .get(header::CONTENT_TYPE)
                 .and_then(|h| h.to_str().ok())
                 .map(|s| s.to_string()),
-            content_length: resp
-                .headers()
-                .get(header::CONTENT_LENGTH)
-                .and_then(|h| h.to_str().ok())
-                .and_then(|s| s.parse().ok()),
+            content_length: None,
             http_code: Some(resp.status().as_u16()),
             response_time: 0,
             contents: None,
+            detected_charset: None,
             error: if resp.status().is_success() {
                 None
             } else {
                 Some(resp.status().to_string())
             },
+            error_code: (!resp.status().is_success()).then_some("upstream_status"),
+            headers: None,
+            retries: 0,
+            upstream_etag,
+            last_modified,
+            cache: None,
         }
     }
 
-    pub fn error(err: Error, url: String) -> PageContent {
+    pub(crate) fn info(resp: Response) -> Self {
+        PageContent {
+            content_length: resp
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|s| s.parse().ok()),
+            ..PageContent::from_headers(&resp)
+        }
+    }
+
+    pub(crate) fn error(err: Error, url: String) -> PageContent {
+        PageContent {
+            http_code: err.status().map(|status| status.as_u16()),
+            error_code: Some(error_code(&err)),
+            ..PageContent::invalid(err.to_string(), url)
+        }
+    }
+
+    /// The request could not be sent, for instance because of a bad method or header
+    pub fn invalid(message: String, url: String) -> PageContent {
         PageContent {
             url,
             content_type: None,
             content_length: None,
-            http_code: err.status().map(|status| status.as_u16()),
+            http_code: None,
             response_time: 0,
             contents: None,
-            error: Some(err.to_string()),
+            detected_charset: None,
+            error: Some(message),
+            headers: None,
+            retries: 0,
+            upstream_etag: None,
+            last_modified: None,
+            cache: None,
+            error_code: Some("invalid_request"),
         }
     }
 
-    pub async fn data(resp: Response) -> PageContent {
-        let content_type = resp
-            .headers()
-            .get(header::CONTENT_TYPE)
-            .and_then(|h| h.to_str().ok())
-            .map(|s| s.to_string());
-
-        let http_code = Some(resp.status().as_u16());
-        let url = resp.url().to_string();
-        let error = if resp.status().is_success() {
-            None
-        } else {
-            Some(resp.status().to_string())
-        };
-        let contents = resp.text().await.ok().filter(|s| !s.is_empty());
-        let content_length = contents.as_ref().map(|c| c.len() as u64);
+    /// Sets the error with its code
+    pub(crate) fn fail(&mut self, code: &'static str, message: String) {
+        self.error = Some(message);
+        self.error_code = Some(code);
+    }
 
+    pub(crate) fn with_error_code(self, code: &'static str) -> Self {
         PageContent {
-            content_length,
-            content_type,
-            http_code,
-            response_time: 0,
-            url,
-            contents,
-            error,
+            error_code: Some(code),
+            ..self
+        }
+    }
+
+    /// The page with its contents, decoded to UTF-8
+    pub async fn data(resp: Response) -> PageContent {
+        PageContent::data_and_body(resp).await.0
+    }
+
+    /// The page with its contents, and the body as it was received
+    pub(crate) async fn data_and_body(resp: Response) -> (PageContent, Bytes) {
+        let content = PageContent::from_headers(&resp);
+        let body = resp.bytes().await.unwrap_or_default();
+        (content.with_body(&body), body)
+    }
+
+    /// Like `data_and_body`, but fails with `too_large` once the body is larger than
+    /// `max_size`, without downloading the rest of it
+    pub(crate) async fn data_and_body_up_to(
+        mut resp: Response,
+        max_size: usize,
+    ) -> (PageContent, Bytes) {
+        let mut content = PageContent::from_headers(&resp);
+        let too_large = format!("Page is larger than {max_size} bytes");
+        if resp
+            .content_length()
+            .is_some_and(|length| length > max_size as u64)
+        {
+            content.fail("too_large", too_large);
+            return (content, Bytes::new());
+        }
+        let mut body = Vec::new();
+        while let Ok(Some(chunk)) = resp.chunk().await {
+            if body.len() + chunk.len() > max_size {
+                content.fail("too_large", too_large);
+                return (content, Bytes::new());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let body = Bytes::from(body);
+        (content.with_body(&body), body)
+    }
+
+    /// Sets the contents, decoded from the body
+    pub(crate) fn with_body(mut self, body: &[u8]) -> Self {
+        if body.is_empty() {
+            return self;
         }
+        let (text, encoding) = decode(self.content_type.as_deref(), body);
+        self.content_length = Some(text.len() as u64);
+        self.detected_charset = Some(encoding.name().to_string());
+        self.contents = Some(text);
+        self
+    }
+}
+
+/// The code of an error of the upstream request
+fn error_code(err: &Error) -> &'static str {
+    if err.is_builder() {
+        "invalid_url"
+    } else if err.is_timeout() {
+        "timeout"
+    } else if err.is_connect() {
+        "connect"
+    } else if err.is_redirect() {
+        "redirect"
+    } else if err.is_body() || err.is_decode() {
+        "body"
+    } else {
+        "request"
+    }
+}
+
+/// The `ETag` and `Last-Modified` of a response
+fn validators(headers: &HeaderMap) -> (Option<String>, Option<String>) {
+    let value = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    (value(header::ETAG), value(header::LAST_MODIFIED))
+}
+
+/// One request in a batch
+#[derive(Deserialize, ToSchema)]
+pub struct BatchRequest {
+    pub url: String,
+    /// Defaults to GET
+    pub method: Option<String>,
+    /// Extra headers sent upstream
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// One result in a streamed batch, `index` is the position of the request in the batch
+#[derive(Serialize, ToSchema)]
+pub struct BatchItem {
+    pub index: usize,
+    #[serde(flatten)]
+    pub content: PageContent,
+}
+
+/// All headers with their values, repeated headers keep all values in order.
+/// Headers in `redacted` (lowercase names) get their values replaced
+pub fn header_values(headers: &HeaderMap, redacted: &[String]) -> BTreeMap<String, Vec<String>> {
+    let mut map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (name, value) in headers {
+        let value = if redacted.iter().any(|r| r == name.as_str()) {
+            REDACTED.to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).to_string()
+        };
+        map.entry(name.to_string()).or_default().push(value);
     }
+    map
 }
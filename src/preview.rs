@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+
+use scraper::{Html, Selector};
+use serde::Serialize;
+use url::Url;
+use utoipa::ToSchema;
+
+/// Link preview of a page, built from its metadata
+#[derive(Serialize, Default, ToSchema)]
+pub struct Preview {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_code: Option<u16>,
+    pub response_time: u32,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub favicon: Option<String>,
+    /// The discovered oEmbed endpoint, JSON is preferred over XML
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oembed: Option<String>,
+    /// All `og:` properties, without the prefix
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub open_graph: BTreeMap<String, String>,
+    /// All `twitter:` properties, without the prefix
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub twitter: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Preview {
+    /// Extracts the preview from an HTML page. Relative links are resolved against `url`
+    pub fn from_html(html: &str, url: String) -> Self {
+        let document = Html::parse_document(html);
+        let base = Url::parse(&url).ok();
+        let resolve = |link: &str| match &base {
+            Some(base) => base.join(link.trim()).map(String::from).ok(),
+            None => Some(link.trim().to_string()),
+        };
+
+        let mut open_graph = BTreeMap::new();
+        let mut twitter = BTreeMap::new();
+        let mut description = None;
+        for meta in document.select(&selector("meta[content]")) {
+            let element = meta.value();
+            let content = element
+                .attr("content")
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+            let name = element
+                .attr("property")
+                .or_else(|| element.attr("name"))
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            if let Some(property) = name.strip_prefix("og:") {
+                open_graph.entry(property.to_string()).or_insert(content);
+            } else if let Some(property) = name.strip_prefix("twitter:") {
+                twitter.entry(property.to_string()).or_insert(content);
+            } else if name == "description" && description.is_none() {
+                description = Some(content);
+            }
+        }
+
+        let title = document
+            .select(&selector("title"))
+            .next()
+            .map(|title| title.text().collect::<String>().trim().to_string());
+        let link = |selectors: &str| {
+            document
+                .select(&selector(selectors))
+                .find_map(|link| link.value().attr("href").and_then(resolve))
+        };
+        let favicon = link(r#"link[rel~="icon"], link[rel="apple-touch-icon"]"#)
+            .or_else(|| resolve("/favicon.ico"));
+        let oembed = link(r#"link[type="application/json+oembed"]"#)
+            .or_else(|| link(r#"link[type="text/xml+oembed"]"#));
+        let canonical_url = open_graph
+            .get("url")
+            .and_then(|url| resolve(url))
+            .or_else(|| link(r#"link[rel="canonical"]"#));
+        let first = |keys: &[(&BTreeMap<String, String>, &str)]| {
+            keys.iter()
+                .find_map(|(map, key)| map.get(*key).filter(|value| !value.is_empty()).cloned())
+        };
+
+        Preview {
+            title: first(&[(&open_graph, "title"), (&twitter, "title")])
+                .or(title.filter(|title| !title.is_empty())),
+            description: first(&[(&open_graph, "description"), (&twitter, "description")])
+                .or(description.filter(|description| !description.is_empty())),
+            site_name: first(&[(&open_graph, "site_name")]),
+            image: first(&[(&open_graph, "image"), (&twitter, "image")]).and_then(|i| resolve(&i)),
+            canonical_url,
+            favicon,
+            oembed,
+            open_graph,
+            twitter,
+            url,
+            ..Preview::default()
+        }
+    }
+
+    pub fn error(error: String, url: String) -> Self {
+        Preview {
+            url,
+            error: Some(error),
+            ..Preview::default()
+        }
+    }
+}
+
+fn selector(selectors: &str) -> Selector {
+    Selector::parse(selectors).expect("Static selector should be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<html><head>
+        <title> Page &amp; title </title>
+        <meta name="description" content="Meta description">
+        <meta property="og:title" content="OG title">
+        <meta property="og:image" content="/image.png">
+        <meta property="og:site_name" content="Example">
+        <meta name="twitter:card" content="summary">
+        <link rel="canonical" href="https://example.com/canonical">
+        <link rel="shortcut icon" href="favicon.png">
+        <link rel="alternate" type="application/json+oembed" href="/oembed?format=json">
+        </head><body></body></html>"#;
+
+    #[test]
+    fn metadata_should_be_extracted() {
+        let preview = Preview::from_html(PAGE, "https://example.com/dir/page".to_string());
+
+        assert_eq!(preview.title.unwrap(), "OG title");
+        assert_eq!(preview.description.unwrap(), "Meta description");
+        assert_eq!(preview.site_name.unwrap(), "Example");
+        assert_eq!(preview.image.unwrap(), "https://example.com/image.png");
+        assert_eq!(
+            preview.canonical_url.unwrap(),
+            "https://example.com/canonical"
+        );
+        assert_eq!(
+            preview.favicon.unwrap(),
+            "https://example.com/dir/favicon.png"
+        );
+        assert_eq!(
+            preview.oembed.unwrap(),
+            "https://example.com/oembed?format=json"
+        );
+        assert_eq!(preview.open_graph["title"], "OG title");
+        assert_eq!(preview.twitter["card"], "summary");
+    }
+
+    #[test]
+    fn title_and_favicon_should_have_fallbacks() {
+        let preview = Preview::from_html(
+            "<title> Page &amp; title </title>",
+            "https://example.com/dir/page".to_string(),
+        );
+
+        assert_eq!(preview.title.unwrap(), "Page & title");
+        assert_eq!(preview.favicon.unwrap(), "https://example.com/favicon.ico");
+        assert!(preview.description.is_none());
+        assert!(preview.oembed.is_none());
+    }
+}
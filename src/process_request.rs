@@ -1,49 +1,541 @@
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::activity::track;
+use crate::builder::mount_path;
+use crate::cache::{cache, is_revalidatable, CachedPage, HIT, MISS};
+use crate::charset::with_charset;
+use crate::conditional::{etag, with_validators};
+use crate::config::config;
+use crate::event_stream::{is_event_stream, with_heartbeat};
 use crate::get_page::GetPage;
+use crate::json_filter::filter_json;
+use crate::page_types::{BatchItem, BatchRequest, PageContent};
+use crate::preview::Preview;
+use crate::rewrite::{rewrite_html, rewrite_html_stream};
+use crate::select::{select, Extract, Selection};
+use crate::websocket;
+use encoding_rs::{Encoding, UTF_8};
+use futures_util::{future, stream, Stream, StreamExt};
+use reqwest::header::{self as upstream_header, HeaderMap, HeaderName};
 use reqwest::Method;
 use tokio::time::Instant;
-use warp::http::{header, HeaderValue};
+use warp::http::{header, HeaderValue, StatusCode};
+use warp::hyper::body::Bytes;
 use warp::hyper::Body;
-use warp::reply::{json, Json, Response};
+use warp::reply::{self, json, Json, Response};
+use warp::ws::Ws;
+use warp::Reply;
 
-pub async fn process_request_info(url: String) -> Json {
+pub async fn process_request_info(url: String, include_headers: bool) -> Response {
     let now = Instant::now();
     println!("info {url}");
-    let page = GetPage::new(url);
+    let mut active = track("info", "GET", &url);
+    let page = GetPage::new(url).with_headers(include_headers);
     let mut content = page.get_page_info().await;
+    active.record(&content);
     content.response_time = now.elapsed().as_millis() as u32;
-    json(&content)
+    active.attach(json(&content))
 }
 
-pub async fn process_request_raw(url: String, method: Method) -> Result<Response, Json> {
+/// The body is relayed as it is, unless it is rewritten, filtered or transcoded to `charset`
+pub async fn process_request_raw(
+    url: String,
+    method: Method,
+    rewrite: bool,
+    filter: Option<String>,
+    charset: Option<&'static Encoding>,
+    accept_encoding: Option<String>,
+    forwarded_headers: HeaderMap,
+) -> Result<Response, Response> {
     let now = Instant::now();
     println!("raw {} {url}", method.as_str());
-    let page = GetPage::new(url);
-    let mut content = page.get_page(method).await;
+    let mut active = track("raw", method.as_str(), &url);
+    let transform = rewrite || filter.is_some() || charset.is_some();
+    let mut forwarded_headers = forwarded_headers;
+    if transform {
+        // The validators and byte ranges of a transformed page are not those of upstream
+        for name in [
+            upstream_header::IF_NONE_MATCH,
+            upstream_header::IF_MODIFIED_SINCE,
+            upstream_header::RANGE,
+            upstream_header::IF_RANGE,
+        ] {
+            forwarded_headers.remove(name);
+        }
+    }
+    let mut page = GetPage::new(url.clone()).with_request_headers(forwarded_headers);
+    if let Some(accept_encoding) = accept_encoding {
+        if config().raw_compression_passthrough && !transform {
+            page = page.with_compression_passthrough(&accept_encoding);
+        }
+    }
+    let (cache_key, cached) = match page.cache_lookup(&method).await {
+        Some((key, cached)) => (Some(key), cached),
+        None => (None, None),
+    };
+    let page = page.revalidating(cached.as_ref());
+    let (upstream, retries) = match page.send(method).await {
+        Ok(upstream) => upstream,
+        Err(mut content) => {
+            active.record(&content);
+            content.response_time = now.elapsed().as_millis() as u32;
+            return Err(active.attach(json(&content)));
+        }
+    };
+    let hit = cached.filter(|_| upstream.status() == reqwest::StatusCode::NOT_MODIFIED);
+    let outcome = if hit.is_some() { HIT } else { MISS };
+    if cache_key.is_some() {
+        cache().unwrap().record(outcome);
+        active.upstream.cache = Some(outcome);
+    }
+    if let Some(ref cached) = hit {
+        if !transform {
+            active.upstream.status = Some(upstream.status().as_u16());
+            return Ok(active.attach(cached_response(cached)));
+        }
+    }
 
-    if let Some(ref _error) = content.error {
-        content.response_time = now.elapsed().as_millis() as u32;
-        return Err(json(&content));
+    // Event streams never end, so they are relayed as they are, even when asked to transform
+    let content_type = upstream.headers().get(upstream_header::CONTENT_TYPE);
+    let event_stream = is_event_stream(content_type.and_then(|c| c.to_str().ok()));
+    // Answers to conditional and range requests are relayed like the page itself
+    let relayed = upstream.status().is_success()
+        || upstream.status() == reqwest::StatusCode::NOT_MODIFIED
+        || upstream.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE;
+    if hit.is_none() && (event_stream || (!transform && relayed)) {
+        active.upstream.status = Some(upstream.status().as_u16());
+        // Plain bodies are kept in the cache as they go through
+        let encoded = upstream
+            .headers()
+            .contains_key(upstream_header::CONTENT_ENCODING);
+        let keep = cache_key
+            .map(|key| (key, url, PageContent::from_headers(&upstream)))
+            .filter(|(_, _, content)| !event_stream && !encoded && is_revalidatable(content));
+        let bytes_in = active.upstream.bytes_in.clone();
+        return Ok(active.attach(stream_response(upstream, bytes_in, event_stream, keep)));
+    }
+
+    // HTML pages that are only rewritten go through as they arrive
+    let content_type = match hit {
+        Some(ref cached) => cached.content.content_type.clone(),
+        None => content_type.and_then(|c| c.to_str().ok()).map(String::from),
+    };
+    let streamed = hit.is_some() || upstream.status().is_success();
+    if rewrite && filter.is_none() && charset.is_none() && streamed && is_html(&content_type) {
+        active.upstream.status = Some(upstream.status().as_u16());
+        let page_url = match hit {
+            Some(ref cached) => cached.content.url.clone(),
+            None => upstream.url().to_string(),
+        };
+        let body = match hit {
+            Some(cached) => stream::once(future::ready(Ok(cached.body))).boxed(),
+            None => {
+                let keep = cache_key
+                    .map(|key| (key, url, PageContent::from_headers(&upstream)))
+                    .filter(|(_, _, content)| is_revalidatable(content));
+                let body = upstream_body(upstream, active.upstream.bytes_in.clone());
+                match keep {
+                    Some((key, url, content)) => {
+                        let cache = cache().unwrap();
+                        cache
+                            .keep_streamed(Box::pin(body), key, url, content)
+                            .boxed()
+                    }
+                    None => body.boxed(),
+                }
+            }
+        };
+        return match rewrite_streamed(body, content_type, &page_url) {
+            Ok(response) => Ok(active.attach(response)),
+            Err(error) => {
+                let mut content =
+                    PageContent::invalid(error, page_url).with_error_code("rewrite_failed");
+                content.response_time = now.elapsed().as_millis() as u32;
+                active.upstream.error = content.error_code;
+                Err(active.attach(json(&content)))
+            }
+        };
+    }
+
+    let mut content = match hit {
+        Some(cached) => cached.page_content(),
+        None => {
+            let (mut content, body) = PageContent::data_and_body(upstream).await;
+            if let Some(key) = cache_key {
+                content.cache = Some(MISS);
+                if is_revalidatable(&content) {
+                    cache().unwrap().insert(&key, &url, &content, body).await;
+                }
+            }
+            content
+        }
+    };
+    content.retries = retries;
+    active.record(&content);
+    if rewrite && is_html(&content.content_type) {
+        if let Err(error) = rewrite_contents(&mut content) {
+            content.fail("rewrite_failed", format!("Could not rewrite page: {error}"));
+        }
+    }
+    if let Some(filter) = filter {
+        filter_contents(&mut content, &filter);
     }
 
-    let mut response = Response::new(Body::from(content.contents.unwrap_or("".to_string())));
-    let headers = response.headers_mut();
-    if let Some(content_length) = content.content_length {
-        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+    if content.error.is_some() {
+        content.response_time = now.elapsed().as_millis() as u32;
+        active.upstream.error = content.error_code;
+        return Err(active.attach(json(&content)));
     }
-    if let Some(content_type) = content.content_type {
-        headers.insert(
+
+    // The contents were decoded, so they are sent in UTF-8 or the requested charset
+    let contents = content.contents.unwrap_or_default();
+    let (body, encoding, _) = charset.unwrap_or(UTF_8).encode(&contents);
+    let transcoded = content
+        .detected_charset
+        .is_some_and(|c| c != encoding.name());
+    let etag = etag(&body, false);
+    let response = Response::new(Body::from(body.into_owned()));
+    let last_modified = content.last_modified.as_deref();
+    let mut response = with_validators(response, Some(&etag), last_modified);
+    if let Some(mut content_type) = content.content_type {
+        if charset.is_some() || transcoded {
+            content_type = with_charset(&content_type, encoding.name());
+        }
+        response.headers_mut().insert(
             header::CONTENT_TYPE,
             HeaderValue::from_str(content_type.as_str()).unwrap(),
         );
     }
+    Ok(active.attach(response))
+}
+
+/// A page from the cache, with the validators it had upstream
+fn cached_response(cached: &CachedPage) -> Response {
+    let content = &cached.content;
+    let response = Response::new(Body::from(cached.body.clone()));
+    let etag = content.upstream_etag.as_deref();
+    let mut response = with_validators(response, etag, content.last_modified.as_deref());
+    let content_type = content.content_type.as_deref();
+    if let Some(value) = content_type.and_then(|c| HeaderValue::from_str(c).ok()) {
+        response.headers_mut().insert(header::CONTENT_TYPE, value);
+    }
+    response
+}
+
+/// The upstream status and body are relayed as they arrive, compressed bodies keep their
+/// encoding.
+/// The received bytes are added to `bytes_in`. Event streams get heartbeats and are kept
+/// out of caches and proxy buffers. With `keep` the body is put in the cache under that key
+/// once it went through. When the client goes away the upstream body is dropped, which
+/// closes the upstream connection
+fn stream_response(
+    upstream: reqwest::Response,
+    bytes_in: Arc<AtomicU64>,
+    event_stream: bool,
+    keep: Option<(String, String, PageContent)>,
+) -> Response {
+    let status = StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::OK);
+    let mut headers = warp::http::HeaderMap::new();
+    for name in [
+        header::CONTENT_TYPE,
+        header::CONTENT_LENGTH,
+        header::CONTENT_ENCODING,
+        header::ETAG,
+        header::LAST_MODIFIED,
+        header::CONTENT_RANGE,
+        header::ACCEPT_RANGES,
+    ] {
+        let value = upstream.headers().get(name.as_str());
+        if let Some(value) = value.and_then(|v| HeaderValue::from_bytes(v.as_bytes()).ok()) {
+            headers.insert(name, value);
+        }
+    }
+
+    let body = upstream_body(upstream, bytes_in);
+    let mut response = if event_stream && !headers.contains_key(header::CONTENT_ENCODING) {
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+        let heartbeat = config().sse_heartbeat;
+        Response::new(Body::wrap_stream(with_heartbeat(body, heartbeat)))
+    } else if let Some((key, url, content)) = keep {
+        let cache = cache().unwrap();
+        let body = cache.keep_streamed(Box::pin(body), key, url, content);
+        Response::new(Body::wrap_stream(body))
+    } else {
+        Response::new(Body::wrap_stream(body))
+    };
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response
+}
+
+/// The upstream body, with the received bytes added to `bytes_in`
+fn upstream_body(
+    upstream: reqwest::Response,
+    bytes_in: Arc<AtomicU64>,
+) -> impl Stream<Item = reqwest::Result<Bytes>> + Send {
+    upstream.bytes_stream().map(move |chunk| {
+        if let Ok(ref chunk) = chunk {
+            bytes_in.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        chunk
+    })
+}
+
+/// An HTML page whose links are rewritten as it goes through, in UTF-8
+fn rewrite_streamed<S>(
+    body: S,
+    content_type: Option<String>,
+    page_url: &str,
+) -> Result<Response, String>
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin + 'static,
+{
+    let config = config();
+    let rewritten = rewrite_html_stream(
+        body,
+        content_type.as_deref(),
+        page_url,
+        config.public_url.as_deref(),
+        mount_path(),
+        config.signing_secret.as_deref(),
+    )
+    .map_err(|error| format!("Could not rewrite page: {error}"))?;
+    let mut response = Response::new(Body::wrap_stream(rewritten));
+    let content_type = with_charset(content_type.as_deref().unwrap_or("text/html"), "utf-8");
+    if let Ok(value) = HeaderValue::from_str(&content_type) {
+        response.headers_mut().insert(header::CONTENT_TYPE, value);
+    }
     Ok(response)
 }
 
-pub async fn process_request_get(url: String, method: Method) -> Json {
+fn is_html(content_type: &Option<String>) -> bool {
+    content_type
+        .as_ref()
+        .is_some_and(|content_type| content_type.contains("text/html"))
+}
+
+/// Rewrites the links of an HTML page to go through this service
+fn rewrite_contents(content: &mut PageContent) -> Result<(), String> {
+    if let Some(ref contents) = content.contents {
+        let config = config();
+        let rewritten = rewrite_html(
+            contents,
+            &content.url,
+            config.public_url.as_deref(),
+            mount_path(),
+            config.signing_secret.as_deref(),
+        )?;
+        content.content_length = Some(rewritten.len() as u64);
+        content.contents = Some(rewritten);
+    }
+    Ok(())
+}
+
+/// Replaces JSON contents with the result of the filter expression. Failures are set as error
+fn filter_contents(content: &mut PageContent, filter: &str) {
+    if content.error.is_some() {
+        return;
+    }
+    match filter_json(content.contents.as_deref().unwrap_or_default(), filter) {
+        Ok(filtered) => {
+            content.content_length = Some(filtered.len() as u64);
+            content.content_type = Some("application/json".to_string());
+            content.contents = Some(filtered);
+        }
+        Err(error) => content.fail("filter_failed", error),
+    }
+}
+
+pub async fn process_request_get(
+    url: String,
+    method: Method,
+    include_headers: bool,
+    filter: Option<String>,
+) -> Response {
     let now = Instant::now();
     println!("get {} {url}", method.as_str());
+    let mut active = track("get", method.as_str(), &url);
+    let page = GetPage::new(url).with_headers(include_headers);
+    let mut content = page.get_page(method).await;
+    active.record(&content);
+    if let Some(filter) = filter {
+        filter_contents(&mut content, &filter);
+        active.upstream.error = content.error_code;
+    }
+    // The response time and retries change with each request, the page does not
+    let retries = std::mem::take(&mut content.retries);
+    let etag = etag(&serde_json::to_vec(&content).unwrap(), true);
+    content.retries = retries;
+    content.response_time = now.elapsed().as_millis() as u32;
+    let last_modified = content.last_modified.as_deref();
+    let response = with_validators(json(&content).into_response(), Some(&etag), last_modified);
+    active.attach(response)
+}
+
+pub async fn process_request_preview(url: String) -> Response {
+    let now = Instant::now();
+    println!("preview {url}");
+    let mut active = track("preview", "GET", &url);
     let page = GetPage::new(url);
+    let content = page.get_page(Method::GET).await;
+    active.record(&content);
+
+    let mut preview = match (content.error, content.contents) {
+        (Some(error), _) => Preview::error(error, content.url),
+        (None, Some(ref contents)) if is_html(&content.content_type) => {
+            Preview::from_html(contents, content.url)
+        }
+        (None, _) => {
+            active.upstream.error = Some("not_html");
+            Preview::error("Not an HTML page".to_string(), content.url)
+        }
+    };
+    preview.http_code = content.http_code;
+    preview.response_time = now.elapsed().as_millis() as u32;
+    active.attach(json(&preview))
+}
+
+pub async fn process_request_select(
+    url: String,
+    selector: String,
+    extract: Extract,
+    limit: usize,
+) -> Response {
+    let now = Instant::now();
+    println!("select {url} {selector}");
+    let mut active = track("select", "GET", &url);
+    let max_size = config().select_max_document_size;
+    let page = GetPage::new(url).with_max_size(max_size);
+    let content = page.get_page(Method::GET).await;
+    active.record(&content);
+
+    let mut status = StatusCode::OK;
+    let mut selection = match (content.error, content.contents) {
+        (Some(error), _) => Selection::error(error, content.url),
+        (None, _) if !is_html(&content.content_type) => {
+            active.upstream.error = Some("not_html");
+            status = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+            Selection::error("Not an HTML page".to_string(), content.url)
+        }
+        (None, contents) => {
+            let contents = contents.unwrap_or_default();
+            match select(&contents, &selector, &extract, limit) {
+                Ok((matches, truncated)) => Selection {
+                    url: content.url,
+                    matches,
+                    truncated,
+                    ..Selection::default()
+                },
+                Err(error) => {
+                    active.upstream.error = Some("select_failed");
+                    Selection::error(error, content.url)
+                }
+            }
+        }
+    };
+    selection.http_code = content.http_code;
+    selection.response_time = now.elapsed().as_millis() as u32;
+    active.attach(reply::with_status(json(&selection), status))
+}
+
+/// Connects to the upstream WebSocket, then upgrades the client connection and bridges them
+pub async fn process_request_ws(ws: Ws, url: String, protocols: Option<String>) -> Response {
+    println!("ws {url}");
+    let mut active = track("ws", "GET", &url);
+    let (upstream, protocol) = match websocket::connect(&url, protocols.as_deref()).await {
+        Ok(connected) => connected,
+        Err(content) => {
+            println!(
+                "ws {url} failed: {}",
+                content.error.as_deref().unwrap_or_default()
+            );
+            active.record(&content);
+            return active.attach(reply::with_status(json(&content), StatusCode::BAD_GATEWAY));
+        }
+    };
+
+    let max_size = config().websocket_max_message_size;
+    let mut response = ws
+        .max_message_size(max_size)
+        .max_frame_size(max_size)
+        .on_upgrade(move |client| websocket::bridge(client, upstream, active))
+        .into_response();
+    if let Some(protocol) = protocol.and_then(|p| HeaderValue::from_str(&p).ok()) {
+        response
+            .headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    response
+}
+
+/// Fetch all pages, at most `batch_concurrency` at a time, results in request order
+pub async fn process_request_batch(requests: Vec<BatchRequest>) -> Json {
+    println!("batch {} requests", requests.len());
+    let contents: Vec<PageContent> = stream::iter(requests)
+        .map(batch_page)
+        .buffered(config().batch_concurrency.max(1))
+        .collect()
+        .await;
+    json(&contents)
+}
+
+/// Fetch all pages, at most `batch_concurrency` at a time, each result is streamed as
+/// a JSON line as soon as it is done
+pub fn process_request_batch_stream(requests: Vec<BatchRequest>) -> Response {
+    println!("batch (stream) {} requests", requests.len());
+    let lines = stream::iter(requests.into_iter().enumerate())
+        .map(|(index, request)| async move {
+            BatchItem {
+                index,
+                content: batch_page(request).await,
+            }
+        })
+        .buffer_unordered(config().batch_concurrency.max(1))
+        .map(|item| {
+            let mut line = serde_json::to_vec(&item).unwrap();
+            line.push(b'\n');
+            Ok::<_, Infallible>(line)
+        });
+
+    let mut response = Response::new(Body::wrap_stream(lines));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    response
+}
+
+async fn batch_page(request: BatchRequest) -> PageContent {
+    let now = Instant::now();
+    let method = request
+        .method
+        .as_deref()
+        .unwrap_or("GET")
+        .to_ascii_uppercase();
+    let method = match Method::from_str(method.as_str()) {
+        Ok(method) => method,
+        Err(_) => return PageContent::invalid(format!("Invalid method {method}"), request.url),
+    };
+    let mut headers = HeaderMap::new();
+    for (name, value) in &request.headers {
+        match (
+            HeaderName::from_str(name),
+            upstream_header::HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => return PageContent::invalid(format!("Invalid header {name}"), request.url),
+        }
+    }
+
+    let _active = track("batch", method.as_str(), &request.url);
+    let page = GetPage::new(request.url).with_request_headers(headers);
     let mut content = page.get_page(method).await;
     content.response_time = now.elapsed().as_millis() as u32;
-    json(&content)
+    content
 }
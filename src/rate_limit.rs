@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::config;
+
+/// How often buckets that filled up again are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many requests a client may send
+pub struct Settings {
+    /// Requests per minute, also the burst a client that was quiet may send at once
+    pub per_minute: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    allowed: u64,
+    limited: u64,
+}
+
+struct State {
+    clients: HashMap<String, Bucket>,
+    pruned: Instant,
+}
+
+/// Token bucket per client. Clients that were quiet long enough for their bucket to fill up
+/// are forgotten, so the counters are those of recent clients
+pub struct RateLimiter {
+    settings: Settings,
+    state: Mutex<State>,
+}
+
+/// The counters of one client, for the admin endpoint
+#[derive(Serialize)]
+pub struct ClientCounters {
+    pub allowed: u64,
+    pub limited: u64,
+    /// Requests the client may still send at once
+    pub remaining: u32,
+}
+
+impl RateLimiter {
+    pub fn new(settings: Settings) -> Self {
+        RateLimiter {
+            settings,
+            state: Mutex::new(State {
+                clients: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        f64::from(self.settings.per_minute)
+    }
+
+    /// The bucket with the tokens added since it was last used
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        let tokens = bucket.tokens + elapsed * self.capacity() / 60.0;
+        bucket.tokens = tokens.min(self.capacity());
+        bucket.updated = now;
+    }
+
+    /// Whether the client may send a request now, which then takes one token
+    pub fn allow(&self, client: &str) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if now.duration_since(state.pruned) >= PRUNE_INTERVAL {
+            state.clients.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * self.capacity() / 60.0 < self.capacity()
+            });
+            state.pruned = now;
+        }
+        let bucket = state.clients.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.capacity(),
+            updated: now,
+            allowed: 0,
+            limited: 0,
+        });
+        self.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.allowed += 1;
+            true
+        } else {
+            bucket.limited += 1;
+            false
+        }
+    }
+
+    /// How long a limited client waits for its next token
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs((60 / u64::from(self.settings.per_minute.max(1))).max(1))
+    }
+
+    /// The counters of all recent clients
+    pub fn counters(&self) -> BTreeMap<String, ClientCounters> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state
+            .clients
+            .iter_mut()
+            .map(|(client, bucket)| {
+                self.refill(bucket, now);
+                let counters = ClientCounters {
+                    allowed: bucket.allowed,
+                    limited: bucket.limited,
+                    remaining: bucket.tokens as u32,
+                };
+                (client.clone(), counters)
+            })
+            .collect()
+    }
+}
+
+/// The client a request counts for: the IP it came from or, when it came through a local
+/// proxy (on the Unix domain socket or loopback), the last address of `X-Forwarded-For`.
+/// None when the client can not be told, such requests are not limited
+pub fn client_key(remote: Option<SocketAddr>, forwarded_for: Option<&str>) -> Option<String> {
+    match remote {
+        Some(remote) if !remote.ip().is_loopback() => Some(remote.ip().to_string()),
+        _ => {
+            let client = forwarded_for?.rsplit(',').next()?.trim();
+            client.parse::<IpAddr>().ok().map(|ip| ip.to_string())
+        }
+    }
+}
+
+/// The rate limiter of the running service, none when `rate_limit_per_minute` is zero
+pub fn rate_limiter() -> Option<&'static RateLimiter> {
+    static RATE_LIMITER: OnceLock<Option<RateLimiter>> = OnceLock::new();
+    RATE_LIMITER
+        .get_or_init(|| {
+            let per_minute = config().rate_limit_per_minute;
+            (per_minute > 0).then(|| RateLimiter::new(Settings { per_minute }))
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_should_be_limited_after_burst() {
+        let limiter = RateLimiter::new(Settings { per_minute: 2 });
+        assert!(limiter.allow("a"));
+        assert!(limiter.allow("a"));
+        assert!(!limiter.allow("a"));
+        assert!(limiter.allow("b"));
+
+        let counters = limiter.counters();
+        assert_eq!(counters["a"].allowed, 2);
+        assert_eq!(counters["a"].limited, 1);
+        assert_eq!(counters["a"].remaining, 0);
+        assert_eq!(counters["b"].remaining, 1);
+        assert_eq!(limiter.retry_after(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn clients_behind_local_proxy_should_be_told_apart() {
+        let remote = |addr: &str| Some(addr.parse::<SocketAddr>().unwrap());
+        let forwarded = Some("203.0.113.9, 198.51.100.7");
+
+        assert_eq!(
+            client_key(remote("192.0.2.1:1234"), forwarded).as_deref(),
+            Some("192.0.2.1")
+        );
+        assert_eq!(
+            client_key(remote("127.0.0.1:1234"), forwarded).as_deref(),
+            Some("198.51.100.7")
+        );
+        assert_eq!(client_key(None, forwarded).as_deref(), Some("198.51.100.7"));
+        assert_eq!(client_key(None, None), None);
+        assert_eq!(client_key(None, Some("unknown")), None);
+    }
+
+    #[test]
+    fn tokens_should_refill_over_time() {
+        // A token every 100ms, far longer than the loop takes
+        let limiter = RateLimiter::new(Settings { per_minute: 600 });
+        for _ in 0..600 {
+            limiter.allow("a");
+        }
+        assert!(!limiter.allow("a"));
+
+        std::thread::sleep(Duration::from_millis(150));
+
+        assert!(limiter.allow("a"));
+    }
+}
@@ -0,0 +1,613 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use url::Url;
+use warp::hyper::body::Bytes;
+
+use crate::cache::{now_millis, CacheBackend, CacheStats, CachedPage, Metadata};
+use crate::page_types::PageContent;
+
+/// All keys of the cache start with this, so the server can be shared with other data
+const PREFIX: &str = "all_origins:";
+/// A Redis command that takes longer than this is a miss
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Connections open at most at the same time
+const POOL_SIZE: usize = 8;
+/// How long pages are kept when `cache_ttl` is not set, so that keys do not pile up
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A reply of the Redis protocol (RESP)
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Integer(i64),
+    /// None for a nil reply, like GET of a missing key
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+/// A connection speaking the Redis protocol
+pub struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Self {
+        Connection {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    pub async fn command(&mut self, args: &[&[u8]]) -> Result<Reply, String> {
+        let mut replies = self.pipeline(&[args.to_vec()]).await?;
+        Ok(replies.remove(0))
+    }
+
+    /// Sends the commands at once and reads their replies, in one round-trip. Fails when one
+    /// of them fails
+    pub async fn pipeline(&mut self, commands: &[Vec<&[u8]>]) -> Result<Vec<Reply>, String> {
+        let mut request = Vec::new();
+        for args in commands {
+            request.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+            for arg in args {
+                request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+                request.extend_from_slice(arg);
+                request.extend_from_slice(b"\r\n");
+            }
+        }
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(&request)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut replies = Vec::with_capacity(commands.len());
+        for _ in commands {
+            replies.push(self.read_reply().await?);
+        }
+        Ok(replies)
+    }
+
+    async fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        let read = self.stream.read_line(&mut line).await;
+        match read.map_err(|e| e.to_string())? {
+            0 => Err("Connection closed".to_string()),
+            _ => Ok(line.trim_end_matches("\r\n").to_string()),
+        }
+    }
+
+    /// Reads one reply. Error replies are errors
+    pub fn read_reply(&mut self) -> BoxFuture<'_, Result<Reply, String>> {
+        Box::pin(async move {
+            let line = self.read_line().await?;
+            let (kind, value) = line.split_at(line.len().min(1));
+            let length = || {
+                value
+                    .parse::<i64>()
+                    .map_err(|_| format!("Invalid reply {line}"))
+            };
+            match kind {
+                "+" => Ok(Reply::Status(value.to_string())),
+                "-" => Err(value.to_string()),
+                ":" => Ok(Reply::Integer(length()?)),
+                "$" => {
+                    let Ok(len) = usize::try_from(length()?) else {
+                        return Ok(Reply::Bulk(None));
+                    };
+                    let mut bulk = vec![0; len + 2];
+                    let read = self.stream.read_exact(&mut bulk).await;
+                    read.map_err(|e| e.to_string())?;
+                    bulk.truncate(len);
+                    Ok(Reply::Bulk(Some(bulk)))
+                }
+                "*" => {
+                    let len = length()?.max(0);
+                    let mut items = Vec::new();
+                    for _ in 0..len {
+                        items.push(self.read_reply().await?);
+                    }
+                    Ok(Reply::Array(items))
+                }
+                _ => Err(format!("Invalid reply {line}")),
+            }
+        })
+    }
+}
+
+/// Pages kept in Redis, so that all replicas share them. Each page is one key holding its
+/// metadata as a JSON line followed by the body, expiring after `ttl` (a day when not set).
+/// A set per URL holds its pages, and a sorted set the URLs by when their pages expire, for
+/// purges. Commands run on a small pool of connections
+pub struct RedisCache {
+    address: String,
+    /// `AUTH` arguments, the password and maybe a user
+    auth: Vec<String>,
+    db: Option<String>,
+    ttl: Duration,
+    /// Connections that are open and not in use
+    idle: Mutex<Vec<Connection>>,
+    /// Limits the open connections to `POOL_SIZE`
+    permits: Semaphore,
+}
+
+impl RedisCache {
+    /// `url` is like `redis://:password@host:6379/0`, only the host is required
+    pub fn new(url: &str, ttl: Option<Duration>) -> Result<Self, String> {
+        let url = Url::parse(url).map_err(|e| format!("{url}: {e}"))?;
+        if url.scheme() != "redis" {
+            return Err(format!("{url}: only redis:// is supported"));
+        }
+        let host = url.host_str().ok_or(format!("{url}: no host"))?;
+        let mut auth = Vec::new();
+        if let Some(password) = url.password() {
+            if !url.username().is_empty() {
+                auth.push(url.username().to_string());
+            }
+            auth.push(password.to_string());
+        }
+        let db = url.path().trim_start_matches('/');
+        Ok(RedisCache {
+            address: format!("{host}:{}", url.port().unwrap_or(6379)),
+            auth,
+            db: (!db.is_empty()).then(|| db.to_string()),
+            ttl: ttl.unwrap_or(DEFAULT_TTL),
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(POOL_SIZE),
+        })
+    }
+
+    async fn connect(&self) -> Result<Connection, String> {
+        let stream = TcpStream::connect(&self.address).await;
+        let mut connection = Connection::new(stream.map_err(|e| e.to_string())?);
+        if !self.auth.is_empty() {
+            let mut args: Vec<&[u8]> = vec![b"AUTH"];
+            args.extend(self.auth.iter().map(|arg| arg.as_bytes()));
+            connection.command(&args).await?;
+        }
+        if let Some(ref db) = self.db {
+            connection.command(&[b"SELECT", db.as_bytes()]).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn command(&self, args: &[&[u8]]) -> Result<Reply, String> {
+        let mut replies = self.pipeline(&[args.to_vec()]).await?;
+        Ok(replies.remove(0))
+    }
+
+    /// Sends the commands on an idle connection, or a new one when all are in use. A connection
+    /// is only reused after its commands succeeded
+    async fn pipeline(&self, commands: &[Vec<&[u8]>]) -> Result<Vec<Reply>, String> {
+        let result = timeout(TIMEOUT, async {
+            let _permit = self.permits.acquire().await.map_err(|e| e.to_string())?;
+            let idle = self.idle.lock().unwrap().pop();
+            let mut connection = match idle {
+                Some(connection) => connection,
+                None => self.connect().await?,
+            };
+            let replies = connection.pipeline(commands).await?;
+            self.idle.lock().unwrap().push(connection);
+            Ok(replies)
+        })
+        .await
+        .unwrap_or_else(|_| Err("Timed out".to_string()));
+        if let Err(ref err) = result {
+            println!(
+                "Cache Redis {} {err}",
+                String::from_utf8_lossy(commands[0][0])
+            );
+        }
+        result
+    }
+
+    /// The cache hands over keys that are already hashed
+    fn page_key(key: &str) -> String {
+        format!("{PREFIX}page:{key}")
+    }
+
+    fn url_key(url: &str) -> String {
+        format!("{PREFIX}url:{url}")
+    }
+
+    fn urls_key() -> String {
+        format!("{PREFIX}urls")
+    }
+
+    async fn members(&self, args: &[&[u8]]) -> Vec<Vec<u8>> {
+        match self.command(args).await {
+            Ok(Reply::Array(members)) => members
+                .into_iter()
+                .filter_map(|member| match member {
+                    Reply::Bulk(member) => member,
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    async fn purge_url(&self, url: &str) -> usize {
+        let url_key = Self::url_key(url);
+        let urls_key = Self::urls_key();
+        let pages = self.members(&[b"SMEMBERS", url_key.as_bytes()]).await;
+        let mut delete: Vec<&[u8]> = vec![b"DEL"];
+        delete.extend(pages.iter().map(Vec::as_slice));
+        let mut commands = vec![
+            vec![b"DEL".as_slice(), url_key.as_bytes()],
+            vec![b"ZREM", urls_key.as_bytes(), url.as_bytes()],
+        ];
+        if !pages.is_empty() {
+            commands.push(delete);
+        }
+        match self.pipeline(&commands).await.as_deref() {
+            Ok([_, _, Reply::Integer(deleted)]) => *deleted as usize,
+            _ => 0,
+        }
+    }
+}
+
+impl CacheBackend for RedisCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<CachedPage>> {
+        Box::pin(async move {
+            let page_key = Self::page_key(key);
+            let Ok(Reply::Bulk(Some(value))) = self.command(&[b"GET", page_key.as_bytes()]).await
+            else {
+                return None;
+            };
+            let split = value.iter().position(|byte| *byte == b'\n')?;
+            let metadata: Metadata = serde_json::from_slice(&value[..split]).ok()?;
+            Some(metadata.cached_page(Bytes::copy_from_slice(&value[split + 1..])))
+        })
+    }
+
+    fn insert<'a>(
+        &'a self,
+        key: &'a str,
+        url: &'a str,
+        content: &'a PageContent,
+        body: Bytes,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let page_key = Self::page_key(key);
+            let mut value = serde_json::to_vec(&Metadata::new(url, content)).unwrap();
+            value.push(b'\n');
+            value.extend_from_slice(&body);
+            let ttl = self.ttl.as_millis().max(1);
+            let now = now_millis();
+            let expires = (now as u128 + ttl).to_string();
+            let (ttl, now) = (ttl.to_string(), now.to_string());
+            let url_key = Self::url_key(url);
+            let urls_key = Self::urls_key();
+            // The pages of a URL are listed as long as the newest of them is kept, and the
+            // URLs whose pages all expired are dropped
+            let _ = self
+                .pipeline(&[
+                    vec![b"SET", page_key.as_bytes(), &value, b"PX", ttl.as_bytes()],
+                    vec![b"SADD", url_key.as_bytes(), page_key.as_bytes()],
+                    vec![b"PEXPIRE", url_key.as_bytes(), ttl.as_bytes()],
+                    vec![
+                        b"ZADD",
+                        urls_key.as_bytes(),
+                        expires.as_bytes(),
+                        url.as_bytes(),
+                    ],
+                    vec![
+                        b"ZREMRANGEBYSCORE",
+                        urls_key.as_bytes(),
+                        b"-inf",
+                        now.as_bytes(),
+                    ],
+                    vec![b"PEXPIRE", urls_key.as_bytes(), ttl.as_bytes()],
+                ])
+                .await;
+        })
+    }
+
+    fn purge<'a>(&'a self, url: Option<&'a str>) -> BoxFuture<'a, usize> {
+        Box::pin(async move {
+            if let Some(url) = url {
+                return self.purge_url(url).await;
+            }
+            let mut purged = 0;
+            let urls_key = Self::urls_key();
+            for url in self
+                .members(&[b"ZRANGE", urls_key.as_bytes(), b"0", b"-1"])
+                .await
+            {
+                purged += self.purge_url(&String::from_utf8_lossy(&url)).await;
+            }
+            purged
+        })
+    }
+
+    fn stats(&self) -> BoxFuture<'_, CacheStats> {
+        Box::pin(async move {
+            CacheStats {
+                backend: "redis",
+                ..CacheStats::default()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    enum Value {
+        String(Vec<u8>),
+        Set(BTreeSet<Vec<u8>>),
+        SortedSet(BTreeMap<Vec<u8>, f64>),
+    }
+
+    type Store = Arc<StdMutex<HashMap<Vec<u8>, (Value, Option<Instant>)>>>;
+
+    fn bulk(value: &[u8]) -> Vec<u8> {
+        let mut reply = format!("${}\r\n", value.len()).into_bytes();
+        reply.extend_from_slice(value);
+        reply.extend_from_slice(b"\r\n");
+        reply
+    }
+
+    /// Runs one command of the stand-in, on unexpired keys
+    fn run(store: &Store, args: &[Vec<u8>]) -> Vec<u8> {
+        let mut store = store.lock().unwrap();
+        store.retain(|_, (_, expires)| expires.is_none_or(|expires| expires > Instant::now()));
+        let millis = |arg: &[u8]| {
+            let millis = String::from_utf8_lossy(arg).parse().unwrap();
+            Some(Instant::now() + Duration::from_millis(millis))
+        };
+        match (args[0].to_ascii_uppercase().as_slice(), &args[1..]) {
+            (b"AUTH" | b"SELECT", _) => b"+OK\r\n".to_vec(),
+            (b"GET", [key]) => match store.get(key) {
+                Some((Value::String(value), _)) => bulk(value),
+                _ => b"$-1\r\n".to_vec(),
+            },
+            (b"SET", [key, value, rest @ ..]) => {
+                let expires = match rest {
+                    [px, ttl] if px.eq_ignore_ascii_case(b"PX") => millis(ttl),
+                    _ => None,
+                };
+                store.insert(key.clone(), (Value::String(value.clone()), expires));
+                b"+OK\r\n".to_vec()
+            }
+            (b"PEXPIRE", [key, ttl]) => match store.get_mut(key) {
+                Some((_, expires)) => {
+                    *expires = millis(ttl);
+                    b":1\r\n".to_vec()
+                }
+                None => b":0\r\n".to_vec(),
+            },
+            (b"SADD" | b"SREM", [key, member]) => {
+                let entry = store.entry(key.clone());
+                let (value, _) = entry.or_insert((Value::Set(BTreeSet::new()), None));
+                let Value::Set(set) = value else {
+                    return b"-WRONGTYPE\r\n".to_vec();
+                };
+                let changed = if args[0].eq_ignore_ascii_case(b"SADD") {
+                    set.insert(member.clone())
+                } else {
+                    set.remove(member)
+                };
+                format!(":{}\r\n", changed as u8).into_bytes()
+            }
+            (b"PTTL", [key]) => match store.get(key) {
+                Some((_, Some(expires))) => {
+                    let ttl = expires.duration_since(Instant::now()).as_millis();
+                    format!(":{ttl}\r\n").into_bytes()
+                }
+                Some((_, None)) => b":-1\r\n".to_vec(),
+                None => b":-2\r\n".to_vec(),
+            },
+            (b"ZADD" | b"ZREM" | b"ZREMRANGEBYSCORE", [key, rest @ ..]) => {
+                let entry = store.entry(key.clone());
+                let (value, _) = entry.or_insert((Value::SortedSet(BTreeMap::new()), None));
+                let Value::SortedSet(set) = value else {
+                    return b"-WRONGTYPE\r\n".to_vec();
+                };
+                let score = |arg: &[u8]| match arg {
+                    b"-inf" => f64::NEG_INFINITY,
+                    arg => String::from_utf8_lossy(arg).parse().unwrap(),
+                };
+                let before = set.len();
+                match (args[0].to_ascii_uppercase().as_slice(), rest) {
+                    (b"ZADD", [score_arg, member]) => {
+                        set.insert(member.clone(), score(score_arg));
+                    }
+                    (b"ZREM", [member]) => {
+                        set.remove(member);
+                    }
+                    (_, [min, max]) => {
+                        let (min, max) = (score(min), score(max));
+                        set.retain(|_, score| *score < min || *score > max);
+                    }
+                    _ => return b"-ERR syntax error\r\n".to_vec(),
+                }
+                format!(":{}\r\n", before.abs_diff(set.len())).into_bytes()
+            }
+            (b"ZRANGE", [key, _, _]) => match store.get(key) {
+                Some((Value::SortedSet(set), _)) => {
+                    let mut reply = format!("*{}\r\n", set.len()).into_bytes();
+                    set.keys().for_each(|member| reply.extend(bulk(member)));
+                    reply
+                }
+                _ => b"*0\r\n".to_vec(),
+            },
+            (b"SMEMBERS", [key]) => match store.get(key) {
+                Some((Value::Set(set), _)) => {
+                    let mut reply = format!("*{}\r\n", set.len()).into_bytes();
+                    set.iter().for_each(|member| reply.extend(bulk(member)));
+                    reply
+                }
+                _ => b"*0\r\n".to_vec(),
+            },
+            (b"DEL", keys) => {
+                let deleted = keys.iter().filter(|key| store.remove(*key).is_some());
+                format!(":{}\r\n", deleted.count()).into_bytes()
+            }
+            _ => b"-ERR unknown command\r\n".to_vec(),
+        }
+    }
+
+    /// A stand-in for redis-server, with the commands used by the cache
+    async fn stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let store = Store::default();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let mut connection = Connection::new(stream);
+                    while let Ok(Reply::Array(args)) = connection.read_reply().await {
+                        let args: Vec<Vec<u8>> = args
+                            .into_iter()
+                            .map(|arg| match arg {
+                                Reply::Bulk(arg) => arg.unwrap_or_default(),
+                                _ => Vec::new(),
+                            })
+                            .collect();
+                        let reply = run(&store, &args);
+                        let stream = connection.stream.get_mut();
+                        if stream.write_all(&reply).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        format!("redis://:secret@{address}/1")
+    }
+
+    fn page() -> PageContent {
+        let mut content = PageContent::invalid(String::new(), "https://r.test/".to_string());
+        content.error = None;
+        content.http_code = Some(200);
+        content.content_type = Some("text/plain".to_string());
+        content.upstream_etag = Some("\"r1\"".to_string());
+        content.contents = Some("not stored".to_string());
+        content
+    }
+
+    #[tokio::test]
+    async fn replicas_should_share_pages() {
+        let url = stand_in().await;
+        let replica = RedisCache::new(&url, None).unwrap();
+        let other = RedisCache::new(&url, None).unwrap();
+        let body = Bytes::from_static(b"shared\nbody");
+
+        replica.insert("a", "https://r.test/", &page(), body).await;
+        replica
+            .insert("b", "https://r.test/", &page(), Bytes::new())
+            .await;
+
+        let cached = other.get("a").await.unwrap();
+        assert_eq!(cached.body.as_ref(), b"shared\nbody");
+        assert_eq!(cached.content.upstream_etag.as_deref(), Some("\"r1\""));
+        assert_eq!(cached.content.content_type.as_deref(), Some("text/plain"));
+        assert!(cached.content.contents.is_none());
+        assert!(other.get("c").await.is_none());
+
+        assert_eq!(other.purge(Some("https://r.test/")).await, 2);
+        assert!(replica.get("a").await.is_none());
+        assert_eq!(replica.purge(None).await, 0);
+    }
+
+    #[tokio::test]
+    async fn pages_should_expire_after_ttl() {
+        let url = stand_in().await;
+        let cache = RedisCache::new(&url, Some(Duration::from_millis(50))).unwrap();
+
+        cache
+            .insert("a", "https://r.test/", &page(), Bytes::new())
+            .await;
+        assert!(cache.get("a").await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(cache.get("a").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn pages_and_urls_should_expire_without_ttl() {
+        let url = stand_in().await;
+        let cache = RedisCache::new(&url, None).unwrap();
+        cache
+            .insert("a", "https://r.test/", &page(), Bytes::new())
+            .await;
+
+        let page_key = RedisCache::page_key("a");
+        let Ok(Reply::Integer(ttl)) = cache.command(&[b"PTTL", page_key.as_bytes()]).await else {
+            panic!("no TTL");
+        };
+        assert!(ttl > 0 && ttl <= DEFAULT_TTL.as_millis() as i64);
+        let urls_key = RedisCache::urls_key();
+        let Ok(Reply::Integer(ttl)) = cache.command(&[b"PTTL", urls_key.as_bytes()]).await else {
+            panic!("no TTL");
+        };
+        assert!(ttl > 0);
+    }
+
+    #[tokio::test]
+    async fn urls_of_expired_pages_should_be_dropped() {
+        let url = stand_in().await;
+        let short = RedisCache::new(&url, Some(Duration::from_millis(20))).unwrap();
+        let long = RedisCache::new(&url, None).unwrap();
+        short
+            .insert("a", "https://old.test/", &page(), Bytes::new())
+            .await;
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        long.insert("b", "https://new.test/", &page(), Bytes::new())
+            .await;
+
+        let urls_key = RedisCache::urls_key();
+        let urls = long
+            .members(&[b"ZRANGE", urls_key.as_bytes(), b"0", b"-1"])
+            .await;
+        assert_eq!(urls, vec![b"https://new.test/".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn commands_should_not_wait_for_each_other() {
+        let url = stand_in().await;
+        let cache = RedisCache::new(&url, None).unwrap();
+        cache
+            .insert("a", "https://r.test/", &page(), Bytes::new())
+            .await;
+        // A connection stuck on a command does not hold up the others
+        let _stuck = cache.permits.acquire().await.unwrap();
+
+        let gets = (0..POOL_SIZE * 2).map(|_| cache.get("a"));
+        let pages = futures_util::future::join_all(gets).await;
+
+        assert!(pages.iter().all(Option::is_some));
+        // The gets ran on several connections at once, no more than the pool allows
+        let idle = cache.idle.lock().unwrap().len();
+        assert!(idle > 1 && idle < POOL_SIZE, "{idle} connections");
+    }
+
+    #[tokio::test]
+    async fn unreachable_server_should_be_a_miss() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let cache = RedisCache::new(&format!("redis://{address}"), None).unwrap();
+
+        cache
+            .insert("a", "https://r.test/", &page(), Bytes::new())
+            .await;
+        assert!(cache.get("a").await.is_none());
+        assert!(RedisCache::new("http://example.com", None).is_err());
+    }
+}
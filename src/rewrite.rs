@@ -0,0 +1,602 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use encoding_rs::Decoder;
+use futures_util::{stream, Stream, StreamExt};
+use html_escape::decode_html_entities;
+use lol_html::html_content::ContentType;
+use lol_html::send::{
+    ElementContentHandlers, EndTagHandler, HtmlRewriter, RewriteStrSettings, Settings,
+};
+use lol_html::{element, rewrite_str, text, OutputSink, Selector};
+use url::form_urlencoded::byte_serialize;
+use url::Url;
+use warp::hyper::body::Bytes;
+
+use crate::charset::{detect, META_PRESCAN};
+use crate::signing::signed_query;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+type Handlers = Vec<(Cow<'static, Selector>, ElementContentHandlers<'static>)>;
+
+/// Attributes holding a single URL, per element selector
+const URL_ATTRIBUTES: [(&str, &str); 17] = [
+    ("a[href]", "href"),
+    ("area[href]", "href"),
+    ("link[href]", "href"),
+    ("img[src]", "src"),
+    ("script[src]", "src"),
+    ("iframe[src]", "src"),
+    ("frame[src]", "src"),
+    ("embed[src]", "src"),
+    ("object[data]", "data"),
+    ("source[src]", "src"),
+    ("track[src]", "src"),
+    ("audio[src]", "src"),
+    ("video[src]", "src"),
+    ("video[poster]", "poster"),
+    ("form[action]", "action"),
+    ("button[formaction]", "formaction"),
+    ("input[formaction]", "formaction"),
+];
+
+/// Rewrites links in an HTML page so that they point to the /raw path of this service.
+///
+/// Links are resolved against the page URL, or the `<base>` of the page if it has one.
+/// With a `public_url` the links are absolute and a `<base>` to the original page is added
+/// (if missing), so that links created by scripts still resolve. Without it the links are
+/// relative to the root of this service and any `<base>` is removed.
+/// `mount_path` is the path the routes are under, like `/proxy`, empty at the root.
+/// With a `signing_secret` the links are signed, so that they are accepted by /raw.
+pub fn rewrite_html(
+    html: &str,
+    page_url: &str,
+    public_url: Option<&str>,
+    mount_path: &str,
+    signing_secret: Option<&str>,
+) -> Result<String, String> {
+    let handlers = handlers(page_url, public_url, mount_path, signing_secret)?;
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: handlers,
+            ..RewriteStrSettings::new_send()
+        },
+    )
+    .map_err(|err| err.to_string())
+}
+
+/// Rewrites an HTML page like `rewrite_html`, as its chunks arrive. The page is decoded by the
+/// charset of its byte order mark, `content_type` or `<meta>`, and sent in UTF-8
+pub fn rewrite_html_stream<S, E>(
+    chunks: S,
+    content_type: Option<&str>,
+    page_url: &str,
+    public_url: Option<&str>,
+    mount_path: &str,
+    signing_secret: Option<&str>,
+) -> Result<impl Stream<Item = Result<Bytes, BoxError>> + Send, String>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + Unpin,
+    E: Into<BoxError>,
+{
+    let output = Output::default();
+    let settings = Settings {
+        element_content_handlers: handlers(page_url, public_url, mount_path, signing_secret)?,
+        ..Settings::new_send()
+    };
+    let rewriting = Rewriting {
+        content_type: content_type.map(String::from),
+        start: Vec::new(),
+        decoder: None,
+        rewriter: Some(HtmlRewriter::new(settings, output.clone())),
+        output,
+    };
+    Ok(stream::unfold(
+        Some((chunks, rewriting)),
+        |state| async move {
+            let (mut chunks, mut rewriting) = state?;
+            loop {
+                let written = match chunks.next().await {
+                    Some(Ok(chunk)) => rewriting.write(&chunk, false),
+                    Some(Err(err)) => return Some((Err(err.into()), None)),
+                    None => {
+                        let ended = rewriting.end().map(|_| rewriting.output.take());
+                        return Some((ended, None));
+                    }
+                };
+                match written {
+                    Ok(()) if rewriting.output.is_empty() => continue,
+                    Ok(()) => {
+                        return Some((Ok(rewriting.output.take()), Some((chunks, rewriting))))
+                    }
+                    Err(err) => return Some((Err(err), None)),
+                }
+            }
+        },
+    ))
+}
+
+/// Collects what the rewriter writes, until it is sent
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    fn take(&self) -> Bytes {
+        Bytes::from(mem::take(&mut *self.0.lock().unwrap()))
+    }
+}
+
+impl OutputSink for Output {
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        self.0.lock().unwrap().extend_from_slice(chunk);
+    }
+}
+
+/// A page being decoded and rewritten
+struct Rewriting {
+    content_type: Option<String>,
+    /// The start of the page, kept until its charset is known
+    start: Vec<u8>,
+    decoder: Option<Decoder>,
+    rewriter: Option<HtmlRewriter<'static, Output>>,
+    output: Output,
+}
+
+impl Rewriting {
+    fn write(&mut self, chunk: &[u8], last: bool) -> Result<(), BoxError> {
+        let decoder = match self.decoder {
+            Some(ref mut decoder) => decoder,
+            None => {
+                self.start.extend_from_slice(chunk);
+                if self.start.len() < META_PRESCAN && !last {
+                    return Ok(());
+                }
+                let encoding = detect(self.content_type.as_deref(), &self.start);
+                self.decoder.insert(encoding.new_decoder())
+            }
+        };
+        let input = match self.start.is_empty() {
+            true => chunk,
+            false => &self.start,
+        };
+        let capacity = decoder.max_utf8_buffer_length(input.len()).unwrap_or(0);
+        let mut text = String::with_capacity(capacity);
+        let _ = decoder.decode_to_string(input, &mut text, last);
+        self.start = Vec::new();
+        let rewriter = self.rewriter.as_mut().ok_or("The page already ended")?;
+        rewriter
+            .write(text.as_bytes())
+            .map_err(|err| err.to_string().into())
+    }
+
+    fn end(&mut self) -> Result<(), BoxError> {
+        self.write(&[], true)?;
+        let rewriter = self.rewriter.take().ok_or("The page already ended")?;
+        rewriter.end().map_err(|err| err.to_string().into())
+    }
+}
+
+/// The handlers that rewrite the links of a page
+fn handlers(
+    page_url: &str,
+    public_url: Option<&str>,
+    mount_path: &str,
+    signing_secret: Option<&str>,
+) -> Result<Handlers, String> {
+    let page_url = Url::parse(page_url).map_err(|err| err.to_string())?;
+    let absolute = public_url.is_some();
+    let proxy = Arc::new(Proxy {
+        base: Mutex::new(page_url.clone()),
+        prefix: format!(
+            "{}{mount_path}/raw?",
+            public_url.unwrap_or("").trim_end_matches('/')
+        ),
+        signing_secret: signing_secret.map(String::from),
+    });
+    let base_seen = Arc::new(AtomicBool::new(false));
+    let style_text = Mutex::new(String::new());
+
+    let mut handlers = vec![
+        element!("base[href]", {
+            let (proxy, base_seen) = (proxy.clone(), base_seen.clone());
+            move |el| {
+                if let Some(href) = el.get_attribute("href") {
+                    let href = decode_html_entities(&href);
+                    let base = proxy.base.lock().unwrap().join(href.trim()).ok();
+                    if let Some(base) = base {
+                        *proxy.base.lock().unwrap() = base;
+                    }
+                }
+                base_seen.store(true, Ordering::Relaxed);
+                if !absolute {
+                    el.remove();
+                }
+                Ok(())
+            }
+        }),
+        element!("head", {
+            let base_seen = base_seen.clone();
+            move |el| {
+                if !absolute {
+                    return Ok(());
+                }
+                let base_seen = base_seen.clone();
+                let base_tag = format!("<base href=\"{}\">", escape_attribute(page_url.as_str()));
+                let add_base: EndTagHandler = Box::new(move |end| {
+                    if !base_seen.load(Ordering::Relaxed) {
+                        end.before(&base_tag, ContentType::Html);
+                    }
+                    Ok(())
+                });
+                if let Some(handlers) = el.end_tag_handlers() {
+                    handlers.push(add_base);
+                }
+                Ok(())
+            }
+        }),
+        element!("meta[http-equiv][content]", {
+            let proxy = proxy.clone();
+            move |el| {
+                let refresh = el.get_attribute("http-equiv").unwrap_or_default();
+                if !refresh.trim().eq_ignore_ascii_case("refresh") {
+                    return Ok(());
+                }
+                let content = el.get_attribute("content").unwrap_or_default();
+                if let Some(content) = proxy.refresh(&decode_html_entities(&content)) {
+                    el.set_attribute("content", &escape_attribute(&content))?;
+                }
+                Ok(())
+            }
+        }),
+        element!("img[srcset], source[srcset]", {
+            let proxy = proxy.clone();
+            move |el| {
+                let srcset = el.get_attribute("srcset").unwrap_or_default();
+                let srcset = proxy.srcset(&decode_html_entities(&srcset));
+                el.set_attribute("srcset", &escape_attribute(&srcset))?;
+                Ok(())
+            }
+        }),
+        element!("[style]", {
+            let proxy = proxy.clone();
+            move |el| {
+                let style = el.get_attribute("style").unwrap_or_default();
+                let style = proxy.css(&decode_html_entities(&style));
+                el.set_attribute("style", &escape_attribute(&style))?;
+                Ok(())
+            }
+        }),
+        text!("style", {
+            let proxy = proxy.clone();
+            move |chunk| {
+                let mut style_text = style_text.lock().unwrap();
+                style_text.push_str(chunk.as_str());
+                if chunk.last_in_text_node() {
+                    let css = proxy.css(&mem::take(&mut *style_text));
+                    chunk.replace(&css, ContentType::Html);
+                } else {
+                    chunk.remove();
+                }
+                Ok(())
+            }
+        }),
+    ];
+    for (selector, attribute) in URL_ATTRIBUTES {
+        let proxy = proxy.clone();
+        handlers.push(element!(selector, move |el| {
+            if let Some(link) = el.get_attribute(attribute) {
+                if let Some(proxied) = proxy.link(&decode_html_entities(&link)) {
+                    el.set_attribute(attribute, &escape_attribute(&proxied))?;
+                }
+            }
+            Ok(())
+        }));
+    }
+    Ok(handlers)
+}
+
+struct Proxy {
+    base: Mutex<Url>,
+    prefix: String,
+    signing_secret: Option<String>,
+}
+
+impl Proxy {
+    /// The proxied link, or None for links that should not be proxied (anchors, data, javascript...)
+    fn link(&self, link: &str) -> Option<String> {
+        let link = link.trim();
+        if link.is_empty() || link.starts_with('#') {
+            return None;
+        }
+        let url = self.base.lock().unwrap().join(link).ok()?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return None;
+        }
+        if let Some(ref secret) = self.signing_secret {
+            let params = vec![
+                ("url".to_string(), url.to_string()),
+                ("rewrite".to_string(), "true".to_string()),
+            ];
+            let query = signed_query(secret, "/raw", params, None);
+            return Some(format!("{}{query}", self.prefix));
+        }
+        let encoded: String = byte_serialize(url.as_str().as_bytes()).collect();
+        Some(format!("{}url={encoded}&rewrite=true", self.prefix))
+    }
+
+    /// A `<meta http-equiv="refresh">` content is a delay, maybe followed by the URL to go to
+    fn refresh(&self, content: &str) -> Option<String> {
+        let (delay, target) = content.split_once([';', ','])?;
+        let target = target.trim_start();
+        let target = match target.get(..3) {
+            Some(url) if url.eq_ignore_ascii_case("url") => {
+                target[3..].trim_start().strip_prefix('=')?.trim_start()
+            }
+            _ => target,
+        };
+        let link = target.trim_matches(['"', '\'']);
+        Some(format!("{}; url={}", delay.trim(), self.link(link)?))
+    }
+
+    /// Each candidate in a srcset is a URL followed by an optional descriptor
+    fn srcset(&self, srcset: &str) -> String {
+        srcset
+            .split(',')
+            .map(|candidate| {
+                let candidate = candidate.trim();
+                let (link, descriptor) = candidate
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((candidate, ""));
+                let link = self.link(link).unwrap_or_else(|| link.to_string());
+                if descriptor.is_empty() {
+                    link
+                } else {
+                    format!("{link} {}", descriptor.trim())
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Rewrites all `url(...)` references in a style sheet
+    fn css(&self, css: &str) -> String {
+        let mut result = String::with_capacity(css.len());
+        let mut rest = css;
+        while let Some(start) = find_ignore_case(rest, "url(") {
+            let (before, after) = rest.split_at(start + "url(".len());
+            result.push_str(before);
+            let Some(end) = after.find(')') else {
+                rest = after;
+                break;
+            };
+            let inner = after[..end].trim();
+            let (quote, link) = match inner.chars().next() {
+                Some(q @ ('"' | '\'')) => (q.to_string(), inner.trim_matches(q)),
+                _ => (String::new(), inner),
+            };
+            match self.link(link) {
+                Some(proxied) => result.push_str(&format!("{quote}{proxied}{quote}")),
+                None => result.push_str(&after[..end]),
+            }
+            rest = &after[end..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack.to_ascii_lowercase().find(needle)
+}
+
+/// Attribute values are read with their entities, and written back escaped
+fn escape_attribute(value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = "https://example.com/dir/page.html";
+
+    #[test]
+    fn links_should_be_proxied() {
+        let html = rewrite_html(
+            r#"<a href="other.html">a</a><img src="/img.png"><form action="https://x.org/s"></form>"#,
+            PAGE,
+            None,
+            "",
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            html,
+            "<a href=\"/raw?url=https%3A%2F%2Fexample.com%2Fdir%2Fother.html&amp;rewrite=true\">a</a>\
+             <img src=\"/raw?url=https%3A%2F%2Fexample.com%2Fimg.png&amp;rewrite=true\">\
+             <form action=\"/raw?url=https%3A%2F%2Fx.org%2Fs&amp;rewrite=true\"></form>"
+        );
+    }
+
+    #[test]
+    fn entities_in_links_should_be_decoded() {
+        let html = rewrite_html(
+            r#"<a href="/search?a=1&amp;b=&quot;2&quot;">s</a>"#,
+            PAGE,
+            None,
+            "",
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            html,
+            "<a href=\"/raw?url=https%3A%2F%2Fexample.com%2Fsearch%3Fa%3D1%26b%3D%25222%2522\
+             &amp;rewrite=true\">s</a>"
+        );
+    }
+
+    #[test]
+    fn anchors_and_other_schemes_should_be_kept() {
+        let html = r##"<a href="#top">a</a><a href="mailto:a@b.c">b</a><img src="data:image/png;base64,AA">"##;
+
+        assert_eq!(rewrite_html(html, PAGE, None, "", None).unwrap(), html);
+    }
+
+    #[test]
+    fn srcset_should_proxy_every_candidate() {
+        let html =
+            rewrite_html(r#"<img srcset="a.png 1x, b.png 2x">"#, PAGE, None, "", None).unwrap();
+
+        assert_eq!(
+            html,
+            "<img srcset=\"/raw?url=https%3A%2F%2Fexample.com%2Fdir%2Fa.png&amp;rewrite=true 1x, \
+             /raw?url=https%3A%2F%2Fexample.com%2Fdir%2Fb.png&amp;rewrite=true 2x\">"
+        );
+    }
+
+    #[test]
+    fn css_urls_should_be_proxied() {
+        let html = rewrite_html(
+            r#"<style>body { background: URL("bg.png") }</style><div style="background: url(/x.png)"></div>"#,
+            PAGE,
+            None,
+            "",
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            html,
+            "<style>body { background: URL(\"/raw?url=https%3A%2F%2Fexample.com%2Fdir%2Fbg.png&rewrite=true\") }</style>\
+             <div style=\"background: url(/raw?url=https%3A%2F%2Fexample.com%2Fx.png&amp;rewrite=true)\"></div>"
+        );
+    }
+
+    #[test]
+    fn base_should_be_used_for_links_and_removed() {
+        let html = rewrite_html(
+            r#"<head><base href="https://cdn.example.com/"></head><img src="a.png">"#,
+            PAGE,
+            None,
+            "",
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            html,
+            "<head></head><img src=\"/raw?url=https%3A%2F%2Fcdn.example.com%2Fa.png&amp;rewrite=true\">"
+        );
+    }
+
+    #[test]
+    fn public_url_should_give_absolute_links_and_add_base() {
+        let html = rewrite_html(
+            r#"<head><title>t</title></head><a href="b.html">b</a>"#,
+            PAGE,
+            Some("https://proxy.example.org/"),
+            "",
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            html,
+            "<head><title>t</title><base href=\"https://example.com/dir/page.html\"></head>\
+             <a href=\"https://proxy.example.org/raw?url=https%3A%2F%2Fexample.com%2Fdir%2Fb.html&amp;rewrite=true\">b</a>"
+        );
+    }
+
+    #[test]
+    fn objects_buttons_and_refreshes_should_be_proxied() {
+        let html = rewrite_html(
+            r#"<object data="movie.swf"></object><button formaction="/send">s</button><meta http-equiv="Refresh" content="5; URL='next.html'">"#,
+            PAGE,
+            None,
+            "",
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            html,
+            "<object data=\"/raw?url=https%3A%2F%2Fexample.com%2Fdir%2Fmovie.swf&amp;rewrite=true\"></object>\
+             <button formaction=\"/raw?url=https%3A%2F%2Fexample.com%2Fsend&amp;rewrite=true\">s</button>\
+             <meta http-equiv=\"Refresh\" content=\"5; url=/raw?url=https%3A%2F%2Fexample.com%2Fdir%2Fnext.html&amp;rewrite=true\">"
+        );
+        let reload = r#"<meta http-equiv="refresh" content="30">"#;
+        assert_eq!(rewrite_html(reload, PAGE, None, "", None).unwrap(), reload);
+    }
+
+    #[tokio::test]
+    async fn stream_should_be_decoded_and_rewritten_across_chunks() {
+        let page = b"<meta charset=\"latin1\"><p>caf\xe9</p><a hr".to_vec();
+        let chunks = [page, b"ef=\"b.html\">b</a>".to_vec()]
+            .map(|chunk| Ok::<_, std::io::Error>(Bytes::from(chunk)));
+
+        let rewritten = rewrite_html_stream(
+            stream::iter(chunks),
+            Some("text/html"),
+            PAGE,
+            None,
+            "",
+            None,
+        )
+        .unwrap()
+        .map(|chunk| chunk.unwrap())
+        .collect::<Vec<_>>()
+        .await
+        .concat();
+
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            "<meta charset=\"latin1\"><p>café</p>\
+             <a href=\"/raw?url=https%3A%2F%2Fexample.com%2Fdir%2Fb.html&amp;rewrite=true\">b</a>"
+        );
+    }
+
+    #[test]
+    fn links_should_be_under_mount_path() {
+        let html = rewrite_html(r#"<a href="b.html">b</a>"#, PAGE, None, "/proxy", None).unwrap();
+        assert_eq!(
+            html,
+            "<a href=\"/proxy/raw?url=https%3A%2F%2Fexample.com%2Fdir%2Fb.html&amp;rewrite=true\">b</a>"
+        );
+
+        let public_url = Some("https://app.example.org");
+        let html = rewrite_html(
+            r#"<a href="b.html">b</a>"#,
+            PAGE,
+            public_url,
+            "/proxy",
+            None,
+        );
+        assert!(html
+            .unwrap()
+            .contains("<a href=\"https://app.example.org/proxy/raw?url="));
+    }
+
+    #[test]
+    fn signing_secret_should_sign_links() {
+        let html =
+            rewrite_html(r#"<a href="b.html">b</a>"#, PAGE, None, "", Some("secret")).unwrap();
+
+        let link = html
+            .strip_prefix("<a href=\"/raw?")
+            .and_then(|html| html.strip_suffix("\">b</a>"))
+            .map(decode_html_entities)
+            .unwrap();
+        assert!(link.starts_with("url=https%3A%2F%2Fexample.com%2Fdir%2Fb.html&rewrite=true&sig="));
+        assert_eq!(crate::signing::verify("secret", "/raw", &link), Ok(()));
+    }
+}
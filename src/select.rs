@@ -0,0 +1,155 @@
+use std::str::FromStr;
+
+use scraper::{ElementRef, Html, Selector};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+
+/// Elements of a page matched by a CSS selector
+#[derive(Serialize, Default, ToSchema)]
+pub struct Selection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_code: Option<u16>,
+    pub response_time: u32,
+    pub url: String,
+    pub matches: Vec<Value>,
+    /// There were more matches than the limit
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Selection {
+    pub fn error(error: String, url: String) -> Self {
+        Selection {
+            url,
+            error: Some(error),
+            ..Selection::default()
+        }
+    }
+}
+
+/// What to return for each matched element
+#[derive(Debug, PartialEq)]
+pub enum Extract {
+    /// The text content
+    Text,
+    /// The outer HTML
+    Html,
+    /// All attributes as an object
+    Attributes,
+    /// The value of one attribute, elements without it are skipped
+    Attribute(String),
+}
+
+impl FromStr for Extract {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Extract::Text),
+            "html" => Ok(Extract::Html),
+            "attributes" => Ok(Extract::Attributes),
+            _ => match s.strip_prefix("attr:") {
+                Some(name) if !name.is_empty() => Ok(Extract::Attribute(name.to_string())),
+                _ => Err(format!(
+                    "Invalid extract '{s}', use text, html, attributes or attr:<name>"
+                )),
+            },
+        }
+    }
+}
+
+/// The extracted values of at most `limit` elements matching `selector`,
+/// and whether there were more matches
+pub fn select(
+    html: &str,
+    selector: &str,
+    extract: &Extract,
+    limit: usize,
+) -> Result<(Vec<Value>, bool), String> {
+    let selector = Selector::parse(selector).map_err(|err| format!("Invalid selector: {err}"))?;
+    let document = Html::parse_document(html);
+
+    let mut values = document
+        .select(&selector)
+        .filter_map(|element| extract_value(element, extract));
+    let matches: Vec<Value> = values.by_ref().take(limit).collect();
+    let truncated = values.next().is_some();
+    Ok((matches, truncated))
+}
+
+fn extract_value(element: ElementRef, extract: &Extract) -> Option<Value> {
+    match extract {
+        Extract::Text => Some(Value::from(element.text().collect::<String>().trim())),
+        Extract::Html => Some(Value::from(element.html())),
+        Extract::Attributes => Some(Value::Object(
+            element
+                .value()
+                .attrs()
+                .map(|(name, value)| (name.to_string(), Value::from(value)))
+                .collect::<Map<_, _>>(),
+        )),
+        Extract::Attribute(name) => element.value().attr(name).map(Value::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const PAGE: &str = r#"<ul>
+        <li><a href="/a" class="x">First</a></li>
+        <li><a href="/b">Second</a></li>
+        <li><a>Third</a></li>
+        </ul>"#;
+
+    #[test]
+    fn text_should_be_extracted() {
+        let (matches, truncated) = select(PAGE, "li a", &Extract::Text, 10).unwrap();
+
+        assert_eq!(
+            matches,
+            vec![json!("First"), json!("Second"), json!("Third")]
+        );
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn attributes_should_be_extracted() {
+        let (matches, _) = select(PAGE, "a", &Extract::Attribute("href".into()), 10).unwrap();
+        assert_eq!(matches, vec![json!("/a"), json!("/b")]);
+
+        let (matches, _) = select(PAGE, "a.x", &Extract::Attributes, 10).unwrap();
+        assert_eq!(matches, vec![json!({"href": "/a", "class": "x"})]);
+    }
+
+    #[test]
+    fn html_should_be_extracted() {
+        let (matches, _) = select(PAGE, "li:nth-child(2) a", &Extract::Html, 10).unwrap();
+
+        assert_eq!(matches, vec![json!(r#"<a href="/b">Second</a>"#)]);
+    }
+
+    #[test]
+    fn matches_should_be_limited() {
+        let (matches, truncated) = select(PAGE, "a", &Extract::Text, 2).unwrap();
+
+        assert_eq!(matches.len(), 2);
+        assert!(truncated);
+    }
+
+    #[test]
+    fn invalid_input_should_be_an_error() {
+        assert!(select(PAGE, "a[", &Extract::Text, 2)
+            .unwrap_err()
+            .starts_with("Invalid selector"));
+        assert_eq!(
+            Extract::from_str("attr:href"),
+            Ok(Extract::Attribute("href".into()))
+        );
+        assert!(Extract::from_str("attr:").is_err());
+    }
+}
@@ -1,38 +1,107 @@
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::fs;
 use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::str::FromStr;
+use std::time::{Instant, SystemTime};
 
+use futures_util::future::BoxFuture;
+use futures_util::{stream, FutureExt};
 use serde::{Deserialize, Serialize};
+use utoipa::IntoParams;
 use warp::http::Method;
 use warp::http::{header, HeaderMap, HeaderValue};
 use warp::hyper::{Body, StatusCode};
+use warp::path::FullPath;
 use warp::reply::Response;
+use warp::ws::Ws;
 use warp::{http, Filter, Rejection, Reply};
 
-use crate::process_request::{process_request_get, process_request_info, process_request_raw};
+use crate::access_log::{log_response, RequestInfo};
+use crate::admin::in_maintenance;
+use encoding_rs::{Encoding, UTF_8};
+
+use crate::charset::encoding_for;
+use crate::compression::compress;
+use crate::conditional::conditional;
+use crate::config::config;
+use crate::openapi::openapi_filter;
+use crate::page_types::BatchRequest;
+use crate::process_request::{
+    process_request_batch, process_request_batch_stream, process_request_get, process_request_info,
+    process_request_preview, process_request_raw, process_request_select, process_request_ws,
+};
+use crate::rate_limit::{client_key, rate_limiter};
+use crate::select::Extract;
+use crate::shutdown::{draining, is_draining};
+use crate::signing::verify;
+
+/// Maximum size of the JSON body for batch requests
+const BATCH_BODY_LIMIT: u64 = 1024 * 1024;
 
 /// The query params accepted by the service
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct QueryParams {
+    /// The page to fetch
+    #[param(required = true)]
     pub url: Option<String>,
+    /// Charset the body is transcoded to, characters outside ASCII are escaped in JSON
     pub charset: Option<String>,
+    /// Include all upstream response headers in the JSON (info and get)
+    pub headers: Option<bool>,
+    /// Rewrite the links of HTML pages to go through this service (raw)
+    pub rewrite: Option<bool>,
+    /// CSS selector of the elements to return (select)
+    pub selector: Option<String>,
+    /// What to return for each element: text, html, attributes or attr:<name> (select)
+    pub extract: Option<String>,
+    /// Maximum number of elements to return (select)
+    pub limit: Option<usize>,
+    /// JMESPath expression applied to JSON contents (get and raw)
+    pub filter: Option<String>,
 }
 
 /// The path for info
 fn info_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
     warp::path("info")
+        .and(signature_filter("info"))
         .and(warp::query::<QueryParams>())
         .and(warp::header::headers_cloned())
         .then(info_handler)
 }
 
+/// Status, content type and length of a page, without its contents
+#[utoipa::path(
+    get,
+    path = "/info",
+    operation_id = "info",
+    tag = "proxy",
+    params(QueryParams),
+    responses(
+        (status = 200, description = "The page info, or why it could not be fetched", body = PageContent),
+        (status = 400, description = "No url or an unknown charset", content_type = "text/plain", body = String),
+    )
+)]
 async fn info_handler(q: QueryParams, headers: HeaderMap) -> Response {
     if let Some(bad_request_response) = check_empty_url(&q) {
         return bad_request_response;
     }
-    let (url, charset) = (q.url.unwrap(), q.charset);
+    let charset = match requested_charset(&q) {
+        Ok(charset) => charset,
+        Err(message) => return bad_request(message),
+    };
+    let url = q.url.unwrap();
+    let include_headers = q.headers.unwrap_or(false);
 
-    let mut content = process_request_info(url).await.into_response();
-    add_headers(headers, charset, &mut content);
+    let content = process_request_info(url, include_headers)
+        .await
+        .into_response();
+    let mut content = json_in_charset(content, charset).await;
+    add_headers(headers, &mut content);
 
     content
 }
@@ -40,22 +109,41 @@ async fn info_handler(q: QueryParams, headers: HeaderMap) -> Response {
 /// The path for get (not same as method GET)
 fn get_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
     warp::path("get")
+        .and(signature_filter("get"))
         .and(warp::query::<QueryParams>())
         .and(warp::method())
         .and(warp::header::headers_cloned())
         .then(get_handler)
 }
 
+/// The page as JSON, with the contents as a string
+#[utoipa::path(
+    get,
+    path = "/get",
+    operation_id = "get",
+    tag = "proxy",
+    params(QueryParams),
+    responses(
+        (status = 200, description = "The page, or why it could not be fetched", body = PageContent),
+        (status = 400, description = "No url or an unknown charset", content_type = "text/plain", body = String),
+    )
+)]
 async fn get_handler(q: QueryParams, m: Method, headers: HeaderMap) -> Response {
     if let Some(bad_request_response) = check_empty_url(&q) {
         return bad_request_response;
     }
-    let (url, charset) = (q.url.unwrap(), q.charset);
+    let charset = match requested_charset(&q) {
+        Ok(charset) => charset,
+        Err(message) => return bad_request(message),
+    };
+    let url = q.url.unwrap();
+    let include_headers = q.headers.unwrap_or(false);
 
-    let mut content = process_request_get(url, reqwest::Method::from_str(m.as_str()).unwrap())
-        .await
-        .into_response();
-    add_headers(headers, charset, &mut content);
+    let method = reqwest::Method::from_str(m.as_str()).unwrap();
+    let content = process_request_get(url, method, include_headers, q.filter).await;
+    let content = conditional(&m, &headers, content);
+    let mut content = json_in_charset(content, charset).await;
+    add_headers(headers, &mut content);
 
     content
 }
@@ -63,42 +151,365 @@ async fn get_handler(q: QueryParams, m: Method, headers: HeaderMap) -> Response
 /// The path for raw
 fn raw_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
     warp::path("raw")
+        .and(signature_filter("raw"))
         .and(warp::query::<QueryParams>())
         .and(warp::method())
         .and(warp::header::headers_cloned())
         .then(raw_handler)
 }
 
+/// The page itself, with its content type
+#[utoipa::path(
+    get,
+    path = "/raw",
+    operation_id = "raw",
+    tag = "proxy",
+    params(QueryParams),
+    responses(
+        (status = 200, description = "The upstream contents, or a JSON description of why the page could not be fetched or transformed", content(
+            ("*/*" = Vec<u8>),
+            ("application/json" = PageContent),
+        )),
+        (status = 400, description = "No url or an unknown charset", content_type = "text/plain", body = String),
+    )
+)]
 async fn raw_handler(q: QueryParams, m: Method, headers: HeaderMap) -> Response {
     if let Some(bad_request_response) = check_empty_url(&q) {
         return bad_request_response;
     }
-    let (url, charset) = (q.url.unwrap(), q.charset);
+    let charset = match requested_charset(&q) {
+        Ok(charset) => charset,
+        Err(message) => return bad_request(message),
+    };
+    let url = q.url.unwrap();
+    let rewrite = q.rewrite.unwrap_or(false);
 
-    let response = process_request_raw(url, reqwest::Method::from_str(m.as_str()).unwrap()).await;
+    let method = reqwest::Method::from_str(m.as_str()).unwrap();
+    let accept_encoding = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|accept| accept.to_str().ok())
+        .map(String::from);
+    let forwarded_headers = forwarded_headers(&headers);
+    let response = process_request_raw(
+        url,
+        method,
+        rewrite,
+        q.filter,
+        charset,
+        accept_encoding,
+        forwarded_headers,
+    )
+    .await;
     match response {
-        Ok(mut content) => {
-            add_headers(headers, charset, &mut content);
+        Ok(content) => {
+            let mut content = conditional(&m, &headers, content);
+            add_headers(headers, &mut content);
             content
         }
         Err(json) => json.into_response(),
     }
 }
 
+/// Client headers that raw passes on to upstream, so event streams can be resumed,
+/// unchanged pages are not sent again and parts of a page can be requested
+const FORWARDED_HEADERS: [&str; 6] = [
+    "accept",
+    "last-event-id",
+    "if-none-match",
+    "if-modified-since",
+    "range",
+    "if-range",
+];
+
+fn forwarded_headers(headers: &HeaderMap) -> reqwest::header::HeaderMap {
+    let mut forwarded = reqwest::header::HeaderMap::new();
+    for name in FORWARDED_HEADERS {
+        let value = headers
+            .get(name)
+            .and_then(|value| reqwest::header::HeaderValue::from_bytes(value.as_bytes()).ok());
+        if let Some(value) = value {
+            forwarded.insert(name, value);
+        }
+    }
+    forwarded
+}
+
+/// The path for preview, metadata of an HTML page
+fn preview_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path("preview")
+        .and(signature_filter("preview"))
+        .and(warp::query::<QueryParams>())
+        .and(warp::header::headers_cloned())
+        .then(preview_handler)
+}
+
+#[utoipa::path(
+    get,
+    path = "/preview",
+    operation_id = "preview",
+    tag = "proxy",
+    params(QueryParams),
+    responses(
+        (status = 200, description = "The metadata of the page, or why it could not be fetched", body = Preview),
+        (status = 400, description = "No url or an unknown charset", content_type = "text/plain", body = String),
+    )
+)]
+async fn preview_handler(q: QueryParams, headers: HeaderMap) -> Response {
+    if let Some(bad_request_response) = check_empty_url(&q) {
+        return bad_request_response;
+    }
+    let charset = match requested_charset(&q) {
+        Ok(charset) => charset,
+        Err(message) => return bad_request(message),
+    };
+    let url = q.url.unwrap();
+
+    let content = process_request_preview(url).await.into_response();
+    let mut content = json_in_charset(content, charset).await;
+    add_headers(headers, &mut content);
+
+    content
+}
+
+/// The path for select, elements of an HTML page matching a CSS selector
+fn select_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path("select")
+        .and(signature_filter("select"))
+        .and(warp::query::<QueryParams>())
+        .and(warp::header::headers_cloned())
+        .then(select_handler)
+}
+
+#[utoipa::path(
+    get,
+    path = "/select",
+    operation_id = "select",
+    tag = "proxy",
+    params(QueryParams),
+    responses(
+        (status = 200, description = "The matching elements, or why the page could not be fetched", body = Selection),
+        (status = 400, description = "No url or selector, or an unknown extract or charset", content_type = "text/plain", body = String),
+        (status = 415, description = "The page is not HTML", body = Selection),
+    )
+)]
+async fn select_handler(q: QueryParams, headers: HeaderMap) -> Response {
+    if let Some(bad_request_response) = check_empty_url(&q) {
+        return bad_request_response;
+    }
+    let charset = match requested_charset(&q) {
+        Ok(charset) => charset,
+        Err(message) => return bad_request(message),
+    };
+    let Some(selector) = q.selector else {
+        return bad_request("No 'selector' query parameter".to_string());
+    };
+    let extract = match Extract::from_str(q.extract.as_deref().unwrap_or("text")) {
+        Ok(extract) => extract,
+        Err(message) => return bad_request(message),
+    };
+    let max_matches = config().select_max_matches;
+    let limit = q.limit.unwrap_or(max_matches).min(max_matches);
+    let url = q.url.unwrap();
+
+    let content = process_request_select(url, selector, extract, limit)
+        .await
+        .into_response();
+    let mut content = json_in_charset(content, charset).await;
+    add_headers(headers, &mut content);
+
+    content
+}
+
+/// The path for ws, a WebSocket bridged to the upstream WebSocket
+fn ws_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path("ws")
+        .and(signature_filter("ws"))
+        .and(warp::ws())
+        .and(warp::query::<QueryParams>())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .then(ws_handler)
+}
+
+#[utoipa::path(
+    get,
+    path = "/ws",
+    operation_id = "ws",
+    tag = "proxy",
+    params(QueryParams),
+    responses(
+        (status = 101, description = "The WebSocket, bridged to the upstream WebSocket"),
+        (status = 400, description = "No ws:// or wss:// url", content_type = "text/plain", body = String),
+    )
+)]
+async fn ws_handler(ws: Ws, q: QueryParams, protocols: Option<String>) -> Response {
+    if let Some(bad_request_response) = check_empty_url(&q) {
+        return bad_request_response;
+    }
+    let url = q.url.unwrap();
+    if !url.starts_with("ws://") && !url.starts_with("wss://") {
+        return bad_request("The 'url' must start with ws:// or wss://".to_string());
+    }
+
+    process_request_ws(ws, url, protocols).await
+}
+
+/// The path for batch, a POST with a JSON array of requests
+fn batch_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path("batch")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(BATCH_BODY_LIMIT))
+        .and(warp::body::json::<Vec<BatchRequest>>())
+        .and(warp::header::headers_cloned())
+        .then(batch_handler)
+}
+
+#[utoipa::path(
+    post,
+    path = "/batch",
+    operation_id = "batch",
+    tag = "proxy",
+    request_body = Vec<BatchRequest>,
+    responses(
+        (status = 200, description = "The pages in request order, or each as a JSON line as soon as it is fetched", content(
+            ("application/json" = Vec<PageContent>),
+            ("application/x-ndjson" = BatchItem),
+        )),
+        (status = 400, description = "Too many requests", content_type = "text/plain", body = String),
+        (status = 403, description = "Batches are not accepted when requests must be signed", content_type = "text/plain", body = String),
+    )
+)]
+async fn batch_handler(requests: Vec<BatchRequest>, headers: HeaderMap) -> Response {
+    if config().signing_secret.is_some() {
+        return forbidden("Batch requests can not be signed".to_string());
+    }
+    let max_requests = config().batch_max_requests;
+    if requests.len() > max_requests {
+        return bad_request(format!("At most {max_requests} requests in a batch"));
+    }
+    let stream = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/x-ndjson"));
+
+    let mut content = if stream {
+        process_request_batch_stream(requests)
+    } else {
+        process_request_batch(requests).await.into_response()
+    };
+    add_headers(headers, &mut content);
+
+    content
+}
+
 fn check_empty_url(query_params: &QueryParams) -> Option<Response> {
     match query_params.url {
-        None => Some(
-            http::response::Builder::new()
-                .status(StatusCode::BAD_REQUEST)
-                .header(header::CONTENT_TYPE, "text/plain")
-                .body(Body::from("No 'url' query parameter"))
-                .into_response(),
-        ),
+        None => Some(bad_request("No 'url' query parameter".to_string())),
         Some(_) => None,
     }
 }
 
-fn add_headers(headers: HeaderMap, charset: Option<String>, content: &mut Response) {
+/// The charset of the `charset` query param, an error when it is unknown
+fn requested_charset(query_params: &QueryParams) -> Result<Option<&'static Encoding>, String> {
+    match query_params.charset.as_deref() {
+        Some(label) => encoding_for(label)
+            .map(Some)
+            .ok_or_else(|| format!("Unknown charset {label}")),
+        None => Ok(None),
+    }
+}
+
+/// The JSON response in the requested charset. Characters outside ASCII are escaped, so the
+/// JSON reads the same in any charset that includes ASCII
+async fn json_in_charset(response: Response, charset: Option<&'static Encoding>) -> Response {
+    let Some(charset) = charset.map(Encoding::output_encoding) else {
+        return response;
+    };
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = warp::hyper::body::to_bytes(body).await.unwrap_or_default();
+    let json = String::from_utf8_lossy(&body);
+    let json = if charset == UTF_8 {
+        json
+    } else {
+        Cow::Owned(escape_non_ascii(&json))
+    };
+    let (body, charset, _) = charset.encode(&json);
+    if let Some(content_type) = parts.headers.get(header::CONTENT_TYPE) {
+        let content_type = format!(
+            "{}; charset={}",
+            content_type.to_str().unwrap_or("application/json"),
+            charset.name()
+        );
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&content_type).unwrap(),
+        );
+    }
+    Response::from_parts(parts, Body::from(body.into_owned()))
+}
+
+/// `\uXXXX` escapes for the characters outside ASCII, which are all in JSON strings
+fn escape_non_ascii(json: &str) -> String {
+    let mut escaped = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            escaped.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                escaped.push_str(&format!("\\u{unit:04x}"));
+            }
+        }
+    }
+    escaped
+}
+
+fn bad_request(message: String) -> Response {
+    http::response::Builder::new()
+        .status(StatusCode::BAD_REQUEST)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(message))
+        .into_response()
+}
+
+fn forbidden(message: String) -> Response {
+    http::response::Builder::new()
+        .status(StatusCode::FORBIDDEN)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(message))
+        .into_response()
+}
+
+#[derive(Debug)]
+struct InvalidSignature(String);
+
+impl warp::reject::Reject for InvalidSignature {}
+
+/// When a signing secret is configured, requests without a valid signature are rejected.
+/// The signed path is `/<route>`, wherever the service is mounted
+fn signature_filter(route: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+        .and_then(move |query: String| async move {
+            match config().signing_secret {
+                Some(ref secret) => verify(secret, &format!("/{route}"), &query)
+                    .map_err(|message| warp::reject::custom(InvalidSignature(message))),
+                None => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+async fn invalid_signature(rejection: Rejection) -> Result<Response, Rejection> {
+    match rejection.find::<InvalidSignature>() {
+        Some(InvalidSignature(message)) => Ok(forbidden(message.clone())),
+        None => Err(rejection),
+    }
+}
+
+fn add_headers(headers: HeaderMap, content: &mut Response) {
     let response_headers = content.headers_mut();
     if let Some(cache_control) = headers.get(header::CACHE_CONTROL) {
         response_headers.insert(header::CACHE_CONTROL, cache_control.clone());
@@ -127,52 +538,294 @@ fn add_headers(headers: HeaderMap, charset: Option<String>, content: &mut Respon
         HeaderValue::from_static("OPTIONS, GET, POST, PATCH, PUT, DELETE"),
     );
     response_headers.insert(header::VIA, HeaderValue::from_static("all_origins_rust"));
-    if let Some(charset) = charset {
-        let content_type = response_headers.get(header::CONTENT_TYPE);
-        if let Some(content_type) = content_type {
-            response_headers.insert(
-                header::CONTENT_TYPE,
-                HeaderValue::from_str(
-                    format!("{}; charset={}", content_type.to_str().unwrap(), charset).as_str(),
-                )
-                .unwrap(),
-            );
+}
+
+/// The path for ready, 503 once the service is shutting down
+fn ready_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::path("ready")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(ready_handler)
+}
+
+#[utoipa::path(
+    get,
+    path = "/ready",
+    operation_id = "ready",
+    tag = "service",
+    responses(
+        (status = 200, description = "The service takes requests", content_type = "text/plain", body = String),
+        (status = 503, description = "The service is shutting down", content_type = "text/plain", body = String),
+    )
+)]
+fn ready_handler() -> impl Reply {
+    let (status, message) = if is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, "draining")
+    } else {
+        (StatusCode::OK, "ready")
+    };
+    warp::reply::with_status(message, status)
+}
+
+/// While in maintenance every request is answered with 503
+fn maintenance_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::any().and_then(|| async {
+        if in_maintenance() {
+            Ok(http::response::Builder::new()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(header::CONTENT_TYPE, "text/plain")
+                .body(Body::from("The service is in maintenance"))
+                .into_response())
+        } else {
+            Err(warp::reject::not_found())
         }
+    })
+}
+
+/// Clients that sent more requests than `rate_limit_per_minute` are answered with 429.
+/// Clients are told apart as by `client_key`
+fn rate_limit_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
+    warp::addr::remote().and(forwarded_for()).and_then(
+        |remote: Option<SocketAddr>, forwarded_for: Option<String>| async move {
+            let client = client_key(remote, forwarded_for.as_deref());
+            match (rate_limiter(), client) {
+                (Some(limiter), Some(client)) if !limiter.allow(&client) => {
+                    Ok(http::response::Builder::new()
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .header(header::CONTENT_TYPE, "text/plain")
+                        .header(header::RETRY_AFTER, limiter.retry_after().as_secs())
+                        .body(Body::from("Too many requests"))
+                        .into_response())
+                }
+                _ => Err(warp::reject::not_found()),
+            }
+        },
+    )
+}
+
+/// The `X-Forwarded-For` header, none when it is missing or not valid
+fn forwarded_for() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Copy {
+    warp::header::optional::<String>("x-forwarded-for")
+        .or(warp::any().map(|| None))
+        .unify()
+}
+
+/// Every request is answered and logged, rejected ones as warp would answer them
+pub fn all_filters() -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Copy {
+    let routes = routes()
+        .recover(|rejection: Rejection| async move {
+            Ok::<_, Infallible>(rejection_response(&rejection))
+        })
+        .unify();
+    request_info()
+        .and(routes)
+        .map(|request, response| log_response(response, request))
+}
+
+/// The routes mounted in another application, which answers the requests they reject.
+/// Only the requests they answer are logged
+pub(crate) fn mounted_filters() -> impl Filter<Extract = (Response,), Error = Rejection> + Copy {
+    request_info()
+        .and(routes())
+        .map(|request, response| log_response(response, request))
+}
+
+/// All routes, with compressed responses
+fn routes() -> impl Filter<Extract = (Response,), Error = Rejection> + Copy {
+    let routes = ready_filter()
+        .or(maintenance_filter())
+        .or(rate_limit_filter())
+        .or(info_filter())
+        .or(get_filter())
+        .or(raw_filter())
+        .or(batch_filter())
+        .or(preview_filter())
+        .or(select_filter())
+        .or(ws_filter())
+        .or(openapi_filter())
+        .recover(invalid_signature);
+
+    warp::header::optional::<String>("accept-encoding")
+        .and(routes)
+        .map(|accept_encoding, reply: _| compress(Reply::into_response(reply), accept_encoding))
+}
+
+/// Answers a rejection the way warp does, so that rejected requests are logged too. As with
+/// warp, the highest status wins and not found only when no route matched at all
+fn rejection_response(rejection: &Rejection) -> Response {
+    use warp::reject::*;
+
+    fn found<T: std::fmt::Display + 'static>(rejection: &Rejection) -> Option<String> {
+        rejection.find::<T>().map(T::to_string)
     }
+    let (status, message) = [
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            found::<UnsupportedMediaType>(rejection),
+        ),
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            found::<PayloadTooLarge>(rejection),
+        ),
+        (
+            StatusCode::LENGTH_REQUIRED,
+            found::<LengthRequired>(rejection),
+        ),
+        (
+            StatusCode::BAD_REQUEST,
+            found::<InvalidQuery>(rejection)
+                .or_else(|| found::<InvalidHeader>(rejection))
+                .or_else(|| found::<MissingHeader>(rejection))
+                .or_else(|| found::<warp::body::BodyDeserializeError>(rejection))
+                .or_else(|| found::<warp::ws::MissingConnectionUpgrade>(rejection)),
+        ),
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            found::<MethodNotAllowed>(rejection),
+        ),
+    ]
+    .into_iter()
+    .find_map(|(status, message)| Some((status, message?)))
+    .unwrap_or_else(|| {
+        if rejection.is_not_found() {
+            (StatusCode::NOT_FOUND, String::new())
+        } else {
+            let message = format!("Unhandled rejection: {rejection:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, message)
+        }
+    });
+
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    if status != StatusCode::NOT_FOUND {
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+    }
+    response
 }
 
-pub fn all_filters() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
-    info_filter().or(get_filter()).or(raw_filter())
+/// The client request, for the access log. It never rejects, so that every request is logged
+fn request_info() -> impl Filter<Extract = (RequestInfo,), Error = Infallible> + Copy {
+    warp::any()
+        .map(|| (Instant::now(), SystemTime::now()))
+        .and(warp::addr::remote())
+        .and(forwarded_for())
+        .and(warp::method())
+        .and(warp::path::full())
+        .map(
+            |(started, timestamp), remote, forwarded_for, method, path: FullPath| RequestInfo {
+                started,
+                timestamp,
+                remote,
+                forwarded_for,
+                method,
+                path: path.as_str().to_string(),
+            },
+        )
 }
 
-/// Start the service
-pub async fn start() -> (
-    impl Future<Output = ()> + Sized,
-    impl Future<Output = ()> + Sized,
-) {
+/// Start the service, on the HTTP and HTTPS ports and on the Unix domain socket, as configured
+pub async fn start() -> io::Result<Vec<BoxFuture<'static, ()>>> {
     const PORT_HTTP: u16 = 38724;
     const PORT_HTTPS: u16 = 38725;
+    let config = config();
+    let mut servers = Vec::new();
 
-    println!("Listening (http) on {PORT_HTTP}");
-    let (_http_addr, http_server) = warp::serve(all_filters()).bind_with_graceful_shutdown(
-        ([0, 0, 0, 0], PORT_HTTP),
-        async move {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("failed to listen to shutdown signal");
-        },
-    );
+    if config.tcp {
+        println!("Listening (http) on {PORT_HTTP}");
+        let (_http_addr, http_server) = warp::serve(all_filters())
+            .bind_with_graceful_shutdown(([0, 0, 0, 0], PORT_HTTP), draining());
+        servers.push(http_server.boxed());
+
+        println!("Listening (https) on {PORT_HTTPS}");
+        let (_https_addr, https_server) = warp::serve(all_filters())
+            .tls()
+            .cert_path("ssl/cert.pem")
+            .key_path("ssl/privkey.pem")
+            .bind_with_graceful_shutdown(([0, 0, 0, 0], PORT_HTTPS), draining());
+        servers.push(https_server.boxed());
+    }
+
+    if let Some(ref path) = config.unix_socket {
+        println!("Listening (unix) on {}", path.display());
+        servers.push(serve_unix(path, config.unix_socket_mode)?.boxed());
+    }
+
+    if servers.is_empty() {
+        println!("Not listening on TCP and no Unix domain socket configured");
+    }
+    Ok(servers)
+}
+
+/// How long accepting connections pauses after an error
+#[cfg(unix)]
+const ACCEPT_ERROR_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Serve on a Unix domain socket. A socket file left by an earlier run is replaced, and the
+/// file is removed when the server stops
+#[cfg(unix)]
+pub(crate) fn serve_unix(path: &Path, mode: u32) -> io::Result<impl Future<Output = ()>> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use tokio::net::UnixListener;
+
+    if fs::symlink_metadata(path).is_ok_and(|metadata| !metadata.file_type().is_socket()) {
+        let message = format!("{} exists and is not a socket", path.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, message));
+    }
+    // The socket is bound in a directory only this user can enter and moved in place once it
+    // has its permissions, so it is never reachable with those of the umask
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let private = path.with_file_name(format!(".{name}.tmp-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&private);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("socket");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&private);
+    let listener = listener?;
+
+    let incoming = stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _addr)) => return Some((Ok::<_, io::Error>(stream), listener)),
+                // An error ends the server, so like for TCP, errors such as too many open
+                // files are waited out
+                Err(err) => {
+                    println!("Could not accept Unix socket connection: {err}");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                }
+            }
+        }
+    });
+    let socket_file = SocketFile(path.to_path_buf());
+    Ok(async move {
+        warp::serve(all_filters())
+            .serve_incoming_with_graceful_shutdown(incoming, draining())
+            .await;
+        drop(socket_file);
+    })
+}
+
+/// Removes the socket file, also when the server is cut off at shutdown
+#[cfg(unix)]
+struct SocketFile(std::path::PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
 
-    println!("Listening (https) on {PORT_HTTPS}");
-    let (_https_addr, https_server) = warp::serve(all_filters())
-        .tls()
-        .cert_path("ssl/cert.pem")
-        .key_path("ssl/privkey.pem")
-        .bind_with_graceful_shutdown(([0, 0, 0, 0], PORT_HTTPS), async move {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("failed to listen to shutdown signal");
-        });
-
-    (http_server, https_server)
+#[cfg(not(unix))]
+pub(crate) fn serve_unix(_path: &Path, _mode: u32) -> io::Result<impl Future<Output = ()>> {
+    Err::<std::future::Ready<()>, _>(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
 }
@@ -0,0 +1,159 @@
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use futures_util::future::{join_all, BoxFuture};
+use tokio::sync::watch;
+
+use crate::activity::active_requests;
+use crate::config::config;
+
+/// The steps of a shutdown, in order
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Phase {
+    Ready,
+    /// Not ready, so that load balancers stop sending requests, which are still accepted
+    Unready,
+    /// The servers no longer accept connections, requests in flight get to finish
+    Draining,
+}
+
+fn phase() -> &'static watch::Sender<Phase> {
+    static PHASE: OnceLock<watch::Sender<Phase>> = OnceLock::new();
+    PHASE.get_or_init(|| watch::channel(Phase::Ready).0)
+}
+
+async fn reached(phase: &watch::Sender<Phase>, target: Phase) {
+    let mut phase = phase.subscribe();
+    let _ = phase.wait_for(|phase| *phase >= target).await;
+}
+
+/// The service is shutting down, it is no longer ready for new requests
+pub fn is_draining() -> bool {
+    *phase().borrow() >= Phase::Unready
+}
+
+/// The service is no longer ready, the servers keep accepting connections for
+/// `Config::shutdown_delay`
+pub fn start_shutdown() {
+    phase().send_if_modified(|phase| {
+        let ready = *phase == Phase::Ready;
+        if ready {
+            *phase = Phase::Unready;
+        }
+        ready
+    });
+}
+
+/// Completes when the service starts draining, the servers then stop accepting connections
+pub async fn draining() {
+    reached(phase(), Phase::Draining).await;
+}
+
+/// Once not ready, waits `delay` before draining. False when the servers stopped before
+async fn close_after_delay<F>(
+    phase: &watch::Sender<Phase>,
+    delay: Duration,
+    servers: &mut F,
+) -> bool
+where
+    F: Future + Unpin,
+{
+    tokio::select! {
+        _ = &mut *servers => return false,
+        _ = reached(phase, Phase::Unready) => {}
+    }
+    println!("Not ready, accepting connections for {}s", delay.as_secs());
+    tokio::select! {
+        _ = &mut *servers => return false,
+        _ = tokio::time::sleep(delay) => {}
+    }
+    phase.send_replace(Phase::Draining);
+    true
+}
+
+/// Waits for SIGTERM or SIGINT, then starts draining
+pub async fn listen_for_signals() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen to SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => println!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => println!("Received SIGINT"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen to shutdown signal");
+        println!("Received Ctrl-C");
+    }
+    start_shutdown();
+}
+
+/// Runs the servers until they are stopped. After a shutdown signal the service is no longer
+/// ready but keeps accepting connections for `shutdown_delay`, so that load balancers see it
+/// first. The servers then stop accepting connections and the requests in flight get
+/// `shutdown_timeout` to finish, those still running then are cut off
+pub async fn run(servers: Vec<BoxFuture<'static, ()>>) {
+    tokio::spawn(listen_for_signals());
+    let servers = join_all(servers);
+    tokio::pin!(servers);
+    if !close_after_delay(phase(), config().shutdown_delay, &mut servers).await {
+        return;
+    }
+
+    let started = Instant::now();
+    let in_flight = active_requests().len();
+    let timeout = config().shutdown_timeout;
+    println!(
+        "Shutting down, draining {in_flight} active requests for at most {}s",
+        timeout.as_secs()
+    );
+    let _ = tokio::time::timeout(timeout, servers).await;
+
+    let cut_off = active_requests();
+    println!(
+        "Shut down in {}ms, {} requests drained, {} cut off",
+        started.elapsed().as_millis(),
+        in_flight.saturating_sub(cut_off.len()),
+        cut_off.len()
+    );
+    for request in cut_off {
+        println!(
+            "Cut off {} {} {} after {}ms",
+            request.route, request.method, request.url, request.elapsed_ms
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn listeners_should_close_after_delay_when_unready() {
+        let phase = watch::channel(Phase::Ready).0;
+        let mut server = Box::pin(reached(&phase, Phase::Draining));
+        let delay = Duration::from_millis(50);
+
+        let started = Instant::now();
+        phase.send_replace(Phase::Unready);
+        assert!(close_after_delay(&phase, delay, &mut server).await);
+
+        assert!(started.elapsed() >= delay);
+        assert!(*phase.borrow() == Phase::Draining);
+        server.await;
+    }
+
+    #[tokio::test]
+    async fn stopped_servers_should_not_wait() {
+        let phase = watch::channel(Phase::Ready).0;
+        let mut servers = Box::pin(async {});
+
+        assert!(!close_after_delay(&phase, Duration::from_secs(60), &mut servers).await);
+        assert!(*phase.borrow() == Phase::Ready);
+    }
+}
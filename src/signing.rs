@@ -0,0 +1,215 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use url::form_urlencoded;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The query param with the signature
+pub const SIG: &str = "sig";
+/// The query param with the time, in seconds since the epoch, after which the signature is
+/// no longer accepted
+pub const EXPIRES: &str = "expires";
+
+/// The signed message is the path with all query params except `sig`, sorted, so a signed
+/// link can not be changed or used for another route
+fn message(path: &str, params: &[(String, String)]) -> String {
+    let mut params: Vec<&(String, String)> =
+        params.iter().filter(|(name, _)| name != SIG).collect();
+    params.sort();
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+    format!("{path}?{query}")
+}
+
+fn mac(secret: &str, path: &str, params: &[(String, String)]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("any key size");
+    mac.update(message(path, params).as_bytes());
+    mac
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The query of a signed link, with `expires` when `expires_in` is given and `sig` last
+pub fn signed_query(
+    secret: &str,
+    path: &str,
+    mut params: Vec<(String, String)>,
+    expires_in: Option<Duration>,
+) -> String {
+    if let Some(expires_in) = expires_in {
+        let expires = (SystemTime::now() + expires_in)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        params.push((EXPIRES.to_string(), expires.to_string()));
+    }
+    let sig = hex(&mac(secret, path, &params).finalize().into_bytes());
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(&params)
+        .append_pair(SIG, &sig)
+        .finish()
+}
+
+/// Checks the `sig` of a request, and that it has not expired
+pub fn verify(secret: &str, path: &str, query: &str) -> Result<(), String> {
+    let params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let sig = param(SIG).ok_or("Missing signature")?;
+    let sig = from_hex(sig).ok_or("Invalid signature")?;
+    mac(secret, path, &params)
+        .verify_slice(&sig)
+        .map_err(|_| "Invalid signature")?;
+
+    if let Some(expires) = param(EXPIRES) {
+        let expires: u64 = expires.parse().map_err(|_| "Invalid expires")?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now > expires {
+            return Err("Signature expired".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// `sign <route> <url> [--expires-in <seconds>] [<param>=<value> ...]` prints a signed link,
+/// prefixed with the public URL when it is configured
+pub fn sign_command(
+    secret: Option<&str>,
+    public_url: Option<&str>,
+    args: &[String],
+) -> Result<String, String> {
+    const USAGE: &str =
+        "Usage: all_origins_rust sign <route> <url> [--expires-in <seconds>] [<param>=<value> ...]";
+    let secret = secret.ok_or("ALL_ORIGINS_SIGNING_SECRET is not set")?;
+    let [route, url, rest @ ..] = args else {
+        return Err(USAGE.to_string());
+    };
+
+    let path = format!("/{}", route.trim_start_matches('/'));
+    let mut params = vec![("url".to_string(), url.to_string())];
+    let mut expires_in = None;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        if arg == "--expires-in" {
+            let seconds = rest.next().and_then(|seconds| seconds.parse().ok());
+            expires_in = Some(Duration::from_secs(seconds.ok_or(USAGE)?));
+        } else {
+            let (name, value) = arg.split_once('=').ok_or(USAGE)?;
+            params.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    let query = signed_query(secret, &path, params, expires_in);
+    let base = public_url.unwrap_or_default().trim_end_matches('/');
+    Ok(format!("{base}{path}?{query}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn signed_query_should_verify() {
+        let query = signed_query(
+            "secret",
+            "/raw",
+            params(&[("url", "https://example.com/?a=1&b=2"), ("rewrite", "true")]),
+            None,
+        );
+
+        assert!(
+            query.starts_with("url=https%3A%2F%2Fexample.com%2F%3Fa%3D1%26b%3D2&rewrite=true&sig=")
+        );
+        assert_eq!(verify("secret", "/raw", &query), Ok(()));
+        assert_eq!(
+            verify("other", "/raw", &query),
+            Err("Invalid signature".to_string())
+        );
+        assert_eq!(
+            verify("secret", "/get", &query),
+            Err("Invalid signature".to_string())
+        );
+    }
+
+    #[test]
+    fn changed_params_should_not_verify() {
+        let query = signed_query("secret", "/raw", params(&[("url", "https://a.com")]), None);
+
+        let changed = query.replace("a.com", "b.com");
+        assert_eq!(
+            verify("secret", "/raw", &changed),
+            Err("Invalid signature".to_string())
+        );
+        let added = format!("rewrite=true&{query}");
+        assert_eq!(
+            verify("secret", "/raw", &added),
+            Err("Invalid signature".to_string())
+        );
+        assert_eq!(
+            verify("secret", "/raw", "url=https%3A%2F%2Fa.com"),
+            Err("Missing signature".to_string())
+        );
+    }
+
+    #[test]
+    fn expired_signature_should_not_verify() {
+        let url = params(&[("url", "https://a.com")]);
+        let valid = signed_query("secret", "/get", url.clone(), Some(Duration::from_secs(60)));
+        assert_eq!(verify("secret", "/get", &valid), Ok(()));
+
+        let mut expired = url;
+        expired.push((EXPIRES.to_string(), "1".to_string()));
+        let expired = signed_query("secret", "/get", expired, None);
+        assert_eq!(
+            verify("secret", "/get", &expired),
+            Err("Signature expired".to_string())
+        );
+    }
+
+    #[test]
+    fn sign_command_should_print_link() {
+        let args = ["raw", "https://a.com", "rewrite=true"].map(String::from);
+
+        let link = sign_command(Some("secret"), Some("https://proxy.test/"), &args).unwrap();
+        assert!(
+            link.starts_with("https://proxy.test/raw?url=https%3A%2F%2Fa.com&rewrite=true&sig=")
+        );
+        let query = link.split_once('?').unwrap().1;
+        assert_eq!(verify("secret", "/raw", query), Ok(()));
+
+        assert!(sign_command(None, None, &args).is_err());
+        assert!(sign_command(Some("secret"), None, &args[..1]).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::borrow::Cow;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{self, Error};
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+use warp::ws::{Message, WebSocket};
+
+use crate::activity::ActiveRequest;
+use crate::circuit_breaker::{circuit_breaker, circuit_key, CIRCUIT_OPEN};
+use crate::config::config;
+use crate::get_page::user_agent;
+use crate::page_types::PageContent;
+
+pub type Upstream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Close code sent to both sides when the idle timeout passes
+const GOING_AWAY: u16 = 1001;
+
+/// Opens the upstream WebSocket, the subprotocols requested by the client are passed on.
+/// Returns the subprotocol upstream selected
+pub async fn connect(
+    url: &str,
+    protocols: Option<&str>,
+) -> Result<(Upstream, Option<String>), PageContent> {
+    let invalid = |code, message: String| {
+        PageContent::invalid(message, url.to_string()).with_error_code(code)
+    };
+    let mut request = url
+        .into_client_request()
+        .map_err(|err| invalid("invalid_url", err.to_string()))?;
+    let headers = request.headers_mut();
+    headers.insert("user-agent", HeaderValue::from_str(&user_agent()).unwrap());
+    if let Some(protocols) = protocols.and_then(|p| HeaderValue::from_str(p).ok()) {
+        headers.insert("sec-websocket-protocol", protocols);
+    }
+
+    let host = circuit_key(url);
+    if let Some(ref host) = host {
+        if !circuit_breaker().allow(host) {
+            return Err(invalid(CIRCUIT_OPEN, CIRCUIT_OPEN.to_string()));
+        }
+    }
+
+    let max_size = config().websocket_max_message_size;
+    let websocket_config = WebSocketConfig {
+        max_message_size: Some(max_size),
+        max_frame_size: Some(max_size),
+        ..WebSocketConfig::default()
+    };
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .map_err(|err| invalid("tls", err.to_string()))?;
+    let connected = tokio_tungstenite::connect_async_tls_with_config(
+        request,
+        Some(websocket_config),
+        true,
+        Some(Connector::NativeTls(connector)),
+    )
+    .await;
+
+    if let Some(ref host) = host {
+        let success = match &connected {
+            Ok(_) => true,
+            Err(Error::Http(response)) => !response.status().is_server_error(),
+            Err(_) => false,
+        };
+        circuit_breaker().record(host, success);
+    }
+    match connected {
+        Ok((upstream, response)) => {
+            let protocol = response
+                .headers()
+                .get("sec-websocket-protocol")
+                .and_then(|protocol| protocol.to_str().ok())
+                .map(String::from);
+            Ok((upstream, protocol))
+        }
+        Err(Error::Http(response)) => {
+            let mut content = invalid(
+                "upstream_status",
+                "Upstream refused the WebSocket".to_string(),
+            );
+            content.http_code = Some(response.status().as_u16());
+            Err(content)
+        }
+        Err(err) => Err(invalid("connect", err.to_string())),
+    }
+}
+
+/// Relays messages both ways until one side closes, fails or both are idle too long.
+/// Pings are answered by each side itself
+pub async fn bridge(client: WebSocket, upstream: Upstream, active: ActiveRequest) {
+    let idle_timeout = config().websocket_idle_timeout;
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+
+    loop {
+        let next = tokio::time::timeout(idle_timeout, async {
+            tokio::select! {
+                message = client_rx.next() => Direction::FromClient(message),
+                message = upstream_rx.next() => Direction::FromUpstream(message),
+            }
+        })
+        .await;
+
+        match next {
+            Ok(Direction::FromClient(Some(Ok(message)))) => {
+                let close = message.is_close();
+                if let Some(message) = to_upstream(message) {
+                    if upstream_tx.send(message).await.is_err() || close {
+                        break;
+                    }
+                }
+            }
+            Ok(Direction::FromUpstream(Some(Ok(message)))) => {
+                let close = matches!(message, tungstenite::Message::Close(_));
+                if let Some(message) = to_client(message) {
+                    if client_tx.send(message).await.is_err() || close {
+                        break;
+                    }
+                }
+            }
+            Ok(Direction::FromClient(message)) => {
+                if let Some(Err(err)) = message {
+                    println!("ws {} client error: {err}", active.url);
+                }
+                let _ = upstream_tx.send(tungstenite::Message::Close(None)).await;
+                break;
+            }
+            Ok(Direction::FromUpstream(message)) => {
+                if let Some(Err(err)) = message {
+                    println!("ws {} upstream error: {err}", active.url);
+                }
+                let _ = client_tx.send(Message::close()).await;
+                break;
+            }
+            Err(_) => {
+                let _ = client_tx
+                    .send(Message::close_with(GOING_AWAY, "Idle timeout"))
+                    .await;
+                let _ = upstream_tx
+                    .send(tungstenite::Message::Close(Some(CloseFrame {
+                        code: CloseCode::from(GOING_AWAY),
+                        reason: Cow::Borrowed("Idle timeout"),
+                    })))
+                    .await;
+                break;
+            }
+        }
+    }
+}
+
+enum Direction {
+    FromClient(Option<Result<Message, warp::Error>>),
+    FromUpstream(Option<Result<tungstenite::Message, Error>>),
+}
+
+fn to_upstream(message: Message) -> Option<tungstenite::Message> {
+    if message.is_text() {
+        let text = message.to_str().ok()?.to_string();
+        Some(tungstenite::Message::Text(text))
+    } else if message.is_binary() {
+        Some(tungstenite::Message::Binary(message.into_bytes()))
+    } else if message.is_close() {
+        let frame = message.close_frame().map(|(code, reason)| CloseFrame {
+            code: CloseCode::from(code),
+            reason: Cow::Owned(reason.to_string()),
+        });
+        Some(tungstenite::Message::Close(frame))
+    } else {
+        None
+    }
+}
+
+fn to_client(message: tungstenite::Message) -> Option<Message> {
+    match message {
+        tungstenite::Message::Text(text) => Some(Message::text(text)),
+        tungstenite::Message::Binary(data) => Some(Message::binary(data)),
+        tungstenite::Message::Close(Some(frame)) => Some(Message::close_with(
+            u16::from(frame.code),
+            frame.reason.into_owned(),
+        )),
+        tungstenite::Message::Close(None) => Some(Message::close()),
+        _ => None,
+    }
+}
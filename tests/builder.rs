@@ -0,0 +1,99 @@
+//! The routes mounted in another application. The builder sets the client and prefix of the
+//! whole process, so these tests have a binary of their own
+use std::sync::OnceLock;
+
+use all_origins_rust::{Builder, Config};
+use warp::filters::BoxedFilter;
+use warp::reply::Response;
+use warp::test::request;
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn proxy() -> &'static BoxedFilter<(Response,)> {
+    static PROXY: OnceLock<BoxedFilter<(Response,)>> = OnceLock::new();
+    PROXY.get_or_init(|| {
+        let mut config = Config::default();
+        config.select_max_matches = 1;
+        Builder::new()
+            .config(config)
+            .prefix("/api/proxy/")
+            .client(|client| {
+                let mut headers = reqwest::header::HeaderMap::new();
+                headers.insert("x-built-by", "builder".parse().unwrap());
+                client.default_headers(headers)
+            })
+            .build()
+            .unwrap()
+    })
+}
+
+#[tokio::test]
+async fn routes_should_be_under_prefix_with_client() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/built.txt"))
+        .and(header("x-built-by", "builder"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("built"))
+        .mount(&server)
+        .await;
+
+    let uri = server.uri();
+    let response = request()
+        .path(&format!("/api/proxy/raw?url={uri}/built.txt"))
+        .reply(proxy())
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.body(), "built");
+
+    let response = request().path("/ready").reply(proxy()).await;
+    assert_eq!(response.status(), 404);
+    let other = Builder::new().prefix("other").build();
+    assert_eq!(
+        other.err().unwrap(),
+        "The routes are already built under another prefix"
+    );
+}
+
+#[tokio::test]
+async fn rewritten_links_should_be_under_prefix() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/links.html"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_raw(r#"<a href="/next.html">n</a>"#, "text/html"),
+        )
+        .mount(&server)
+        .await;
+
+    let uri = server.uri();
+    let response = request()
+        .path(&format!("/api/proxy/raw?url={uri}/links.html&rewrite=true"))
+        .reply(proxy())
+        .await;
+
+    assert_eq!(response.status(), 200);
+    let body = String::from_utf8(response.body().to_vec()).unwrap();
+    assert!(
+        body.starts_with("<a href=\"/api/proxy/raw?url=http%3A%2F%2F"),
+        "{body}"
+    );
+}
+
+#[tokio::test]
+async fn config_should_be_used() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/list.html"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw("<p>1</p><p>2</p>", "text/html"))
+        .mount(&server)
+        .await;
+
+    let uri = server.uri();
+    let response = request()
+        .path(&format!("/api/proxy/select?url={uri}/list.html&selector=p"))
+        .reply(proxy())
+        .await;
+    let selection: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(selection["matches"], serde_json::json!(["1"]));
+    assert_eq!(selection["truncated"], true);
+}